@@ -14,17 +14,35 @@ object IgyNetwork {
         }
     }
 
+    // Native calls throw IllegalArgumentException for values the engine rejects and
+    // RuntimeException if the engine itself fails.
     external fun measureNetworkStats(targetIp: String): String?
+    external fun runDiagnostics(configJson: String): String?
+    external fun cancelDiagnostics()
+    external fun getTunnelRanking(): String?
     external fun runVpnLoop(fd: Int)
     external fun runPassiveShield(fd: Int)
+    external fun stopEngine()
     external fun getNativeBlockedCount(): Long
     external fun getCoreHealth(): String?
+    external fun getTunConfig(): String?
+    external fun getUidTraffic(): String?
     external fun getEnergySavings(): String?
     external fun setBandwidthLimit(limitMbps: Int)
     external fun toggleStealthMode(enabled: Boolean)
     external fun setOutlineKey(key: String)
+    external fun setServerSubscription(content: String): String?
+    external fun setUdpGateway(addr: String)
     external fun setAllowedDomains(domains: String)
     external fun setAllowedUids(uids: LongArray)
+    external fun setRoutingPolicy(policyJson: String): String?
+    external fun setGeoIpDatabase(path: String): String?
+    external fun setAcl(aclText: String, cacheDir: String): String?
+    external fun setKillSwitch(enabled: Boolean, allowCidrs: String): String?
+    external fun setRuntimeConfig(configJson: String): String?
+    external fun applyConfig(configJson: String): String?
+    external fun startCapture(configJson: String): String?
+    external fun stopCapture(path: String): String?
 
     fun isAvailable() = isLibLoaded
 
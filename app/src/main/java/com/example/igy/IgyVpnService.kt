@@ -111,12 +111,20 @@ class IgyVpnService : VpnService(), Runnable {
             val ssKey = IgyPreferences.getOutlineKey(this)
             
             // B. ESTABLISH TUNNEL
+            // The interface is whatever the engine's validated `tun` config says
+            val tun = tunConfig()
+            val (v4Addr, v4Len) = splitCidr(tun.getString("ipv4"), 32)
             val builder = Builder()
                 .setSession("IgyShield")
-                .addAddress("10.0.0.1", 24)
+                .addAddress(v4Addr, v4Len)
                 .addRoute("0.0.0.0", 0)
-                .setMtu(1280)
+                .setMtu(tun.getInt("mtu"))
                 .setConfigureIntent(PendingIntent.getActivity(this, 0, Intent(this, MainActivity::class.java), PendingIntent.FLAG_IMMUTABLE))
+            if (!tun.isNull("ipv6")) {
+                // IPv6 only goes into the tunnel when the engine expects it
+                val (v6Addr, v6Len) = splitCidr(tun.getString("ipv6"), 128)
+                builder.addAddress(v6Addr, v6Len).addRoute("::", 0)
+            }
 
             if (IgyPreferences.getLocalBypass(this)) builder.allowBypass()
             builder.addDnsServer("1.1.1.1")
@@ -156,7 +164,7 @@ class IgyVpnService : VpnService(), Runnable {
                             } catch (e: Exception) {}
                         }
                         if (IgyNetwork.isAvailable()) {
-                            IgyNetwork.setAllowedUids(uids.toLongArray())
+                            applySetting("ALLOWED_UIDS") { IgyNetwork.setAllowedUids(uids.toLongArray()) }
                         }
                         TrafficEvent.log("VPN_FOCUS >> LOCKING_DOWN_${uids.size}_APPS")
                     }
@@ -168,7 +176,7 @@ class IgyVpnService : VpnService(), Runnable {
                     // VPN GLOBAL MODE
                     TrafficEvent.log("VPN >> ACTIVE")
                     if (IgyNetwork.isAvailable()) {
-                        IgyNetwork.setAllowedUids(longArrayOf()) // Clear list for global
+                        applySetting("ALLOWED_UIDS") { IgyNetwork.setAllowedUids(longArrayOf()) } // Clear list for global
                     }
                     TrafficEvent.log("VPN >> PROTECTING_WHOLE_DEVICE")
                 }
@@ -186,18 +194,18 @@ class IgyVpnService : VpnService(), Runnable {
             // C. HANDOVER TO NATIVE ENGINE
             val fd = vpnInterface!!.fd
             if (IgyNetwork.isAvailable()) {
-                IgyNetwork.setAllowedDomains(IgyPreferences.getAllowedDomains(this))
-                IgyNetwork.setOutlineKey(ssKey)
+                applySetting("ALLOWED_DOMAINS") { IgyNetwork.setAllowedDomains(IgyPreferences.getAllowedDomains(this)) }
+                val keyAccepted = applySetting("OUTLINE_KEY") { IgyNetwork.setOutlineKey(ssKey) }
 
                 if (!isStealth) {
                     // NORMAL FOCUS: Always use Passive Shield (to swallow background traffic)
                     TrafficEvent.log("NORMAL_FOCUS >> ENGAGED")
                     IgyNetwork.runPassiveShield(fd)
-                } else if (ssKey.isNotEmpty()) {
+                } else if (ssKey.isNotEmpty() && keyAccepted) {
                     // VPN MODES (Global/Focus): Use VpnLoop if key is present
                     IgyNetwork.runVpnLoop(fd)
                 } else {
-                    // Fallback to Passive Shield if no key is found
+                    // Fallback to Passive Shield if no usable key is found
                     TrafficEvent.log("VPN >> PASSIVE_MODE: NO_KEY")
                     IgyNetwork.runPassiveShield(fd)
                 }
@@ -213,6 +221,28 @@ class IgyVpnService : VpnService(), Runnable {
         }
     }
 
+    // The engine rejected a value (bad key, bad UID list): log it and start without it
+    private fun applySetting(name: String, apply: () -> Unit): Boolean {
+        return try {
+            apply()
+            true
+        } catch (e: IllegalArgumentException) {
+            TrafficEvent.log("CORE >> CONFIG_REJECTED: ${name}_${e.message}")
+            false
+        }
+    }
+
+    // The engine's `tun` config; the defaults in config.rs when the engine is not loaded
+    private fun tunConfig(): org.json.JSONObject {
+        val json = if (IgyNetwork.isAvailable()) IgyNetwork.getTunConfig() else null
+        return org.json.JSONObject(json ?: """{"mtu":1280,"ipv4":"10.0.0.1/24","ipv6":"fd00:1::1/64"}""")
+    }
+
+    private fun splitCidr(cidr: String, hostLen: Int): Pair<String, Int> {
+        val parts = cidr.trim().split("/", limit = 2)
+        return parts[0] to (parts.getOrNull(1)?.toInt() ?: hostLen)
+    }
+
     private fun stopVpn() {
         if (!isRunning) {
             TrafficEvent.log("ALREADY_OFFLINE")
@@ -220,6 +250,8 @@ class IgyVpnService : VpnService(), Runnable {
         }
         isRunning = false
         TrafficEvent.setVpnActive(false)
+        // End the native session before its TUN goes away under it
+        if (IgyNetwork.isAvailable()) IgyNetwork.stopEngine()
         try { vpnInterface?.close() } catch (e: Exception) {}
         vpnInterface = null
         
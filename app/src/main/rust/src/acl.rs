@@ -0,0 +1,141 @@
+use serde::Serialize;
+use shadowsocks_service::acl::AccessControl;
+use std::net::IpAddr;
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::common::*;
+
+// --- SS-LOCAL ACL: BYPASS / PROXY LISTS ---
+// shadowsocks-rust only loads ACLs from a file and silently turns anything it cannot read as
+// an address into a regex. We validate line by line first so the UI can point at the exact
+// rule, then hand the file to ss-local.
+
+const ACL_FILE_PREFIX: &str = "igy_ss_local";
+
+// Sets each install's file apart from any other install running at the same time
+static ACL_FILE_SEQ: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct AclError {
+    pub line: usize,
+    pub rule: String,
+    pub reason: String,
+}
+
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct AclSummary {
+    // "proxy_all" or "bypass_all": what happens to hosts no rule matches
+    pub mode: &'static str,
+    pub bypass_rules: usize,
+    pub proxy_rules: usize,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Section {
+    Bypass,
+    Proxy,
+}
+
+/// Checks ACL text with the same grammar ss-local uses, restricted to the local-side sections.
+/// Reports every bad line rather than stopping at the first one.
+pub fn validate(text: &str) -> Result<AclSummary, Vec<AclError>> {
+    let mut summary = AclSummary { mode: "proxy_all", ..Default::default() };
+    let mut errors = Vec::new();
+    let mut section = Section::Bypass;
+
+    for (i, raw) in text.lines().enumerate() {
+        let line = raw.trim();
+        if line.is_empty() || raw.starts_with('#') {
+            continue;
+        }
+        let mut fail = |reason: String| errors.push(AclError { line: i + 1, rule: line.to_string(), reason });
+        if !line.is_ascii() {
+            // ss-local would skip it without a word
+            fail("NON_ASCII_RULE".to_string());
+            continue;
+        }
+
+        let checked = if let Some(domain) = line.strip_prefix("||").or_else(|| line.strip_prefix('|')) {
+            check_domain(domain)
+        } else if line.starts_with('[') && line.ends_with(']') {
+            match line {
+                "[proxy_all]" | "[accept_all]" => summary.mode = "proxy_all",
+                "[bypass_all]" | "[reject_all]" => summary.mode = "bypass_all",
+                "[bypass_list]" | "[black_list]" => section = Section::Bypass,
+                "[proxy_list]" | "[white_list]" => section = Section::Proxy,
+                s if s.starts_with("[outbound_") => fail(format!("SERVER_ONLY_SECTION: {}", s)),
+                s => fail(format!("UNKNOWN_SECTION: {}", s)),
+            }
+            continue;
+        } else {
+            check_address_or_regex(line)
+        };
+
+        match checked {
+            Ok(()) if section == Section::Bypass => summary.bypass_rules += 1,
+            Ok(()) => summary.proxy_rules += 1,
+            Err(reason) => fail(reason),
+        }
+    }
+
+    if errors.is_empty() { Ok(summary) } else { Err(errors) }
+}
+
+fn check_domain(domain: &str) -> Result<(), String> {
+    let domain = domain.trim_end_matches('.');
+    if domain.is_empty() {
+        return Err("EMPTY_DOMAIN".to_string());
+    }
+    let valid = domain.split('.').all(|label| {
+        !label.is_empty() && label.len() <= 63 && label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+    });
+    if !valid {
+        return Err(format!("INVALID_DOMAIN: {}", domain));
+    }
+    Ok(())
+}
+
+fn check_address_or_regex(rule: &str) -> Result<(), String> {
+    // Anything that starts out as an address must be a valid one; ss-local would otherwise
+    // quietly treat `10.0.0.0/33` as a regex that never matches
+    let head = rule.split('/').next().unwrap_or(rule);
+    if head.parse::<IpAddr>().is_ok() {
+        return crate::cidr::parse_cidr(rule).map(|_| ());
+    }
+    regex::bytes::RegexBuilder::new(rule)
+        .unicode(false)
+        .build()
+        .map(|_| ())
+        .map_err(|e| format!("INVALID_REGEX: {}", e.to_string().lines().last().unwrap_or("")))
+}
+
+/// Validates `text`, writes it under `cache_dir` and loads it the way ss-local will. An empty
+/// text removes the ACL. Takes effect when the next ss-local instance starts.
+pub fn install(text: &str, cache_dir: &str) -> Result<AclSummary, Vec<AclError>> {
+    let io_error = |e: std::io::Error| vec![AclError { line: 0, rule: String::new(), reason: format!("ACL_LOAD_FAILED: {}", e) }];
+
+    if text.trim().is_empty() {
+        if let Ok(mut acl) = SS_ACL.write() {
+            *acl = None;
+        }
+        return Ok(AclSummary::default());
+    }
+    let summary = validate(text)?;
+    let seq = ACL_FILE_SEQ.fetch_add(1, Ordering::Relaxed);
+    let path = Path::new(cache_dir).join(format!("{}.{}.{}.acl", ACL_FILE_PREFIX, std::process::id(), seq));
+    // create_new: never load a file some other writer still owns
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .and_then(|mut f| f.write_all(text.as_bytes()))
+        .map_err(io_error)?;
+    let loaded = AccessControl::load_from_file(&path);
+    let _ = std::fs::remove_file(&path);
+    let acl = loaded.map_err(io_error)?;
+    if let Ok(mut current) = SS_ACL.write() {
+        *current = Some(acl);
+    }
+    Ok(summary)
+}
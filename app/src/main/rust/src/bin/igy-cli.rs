@@ -0,0 +1,191 @@
+use igy_core::{CaptureConfig, Engine, EngineConfig, Status, TunConfig};
+use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd};
+use std::process::ExitCode;
+use std::sync::Arc;
+
+// --- IGY CLI: THE ENGINE ON DESKTOP LINUX ---
+// Runs the same engine the app does, on a real TUN device or on one end of a socketpair.
+// Only traffic routed into the device goes through the engine; keep the route to the
+// upstream server outside it.
+
+const USAGE: &str = "usage: igy-cli [--config FILE] [--tun NAME | --socketpair] [--shield] [--capture FILE]
+
+  --config FILE   engine config JSON (same document as applyConfig); defaults otherwise
+  --tun NAME      create TUN device NAME with the config's `tun` addresses (needs CAP_NET_ADMIN)
+  --socketpair    run on one end of a SOCK_SEQPACKET pair and print what the engine writes back
+  --shield        run the passive shield instead of the VPN loop
+  --capture FILE  record the session's packets and write them to FILE as pcapng on exit";
+
+struct Options {
+    config: Option<String>,
+    tun: Option<String>,
+    shield: bool,
+    capture: Option<String>,
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut options = Options { config: None, tun: None, shield: false, capture: None };
+    let mut socketpair = false;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => options.config = Some(args.next().ok_or("--config needs a file")?),
+            "--tun" => options.tun = Some(args.next().ok_or("--tun needs a name")?),
+            "--socketpair" => socketpair = true,
+            "--shield" => options.shield = true,
+            "--capture" => options.capture = Some(args.next().ok_or("--capture needs a file")?),
+            "-h" | "--help" => return Err(String::new()),
+            other => return Err(format!("unknown argument: {}", other)),
+        }
+    }
+    if socketpair == options.tun.is_some() {
+        return Err("pick one of --tun NAME or --socketpair".to_string());
+    }
+    Ok(options)
+}
+
+fn load_config(path: Option<&str>) -> Result<EngineConfig, String> {
+    let json = match path {
+        Some(path) => std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?,
+        None => "{}".to_string(),
+    };
+    EngineConfig::from_json(&json).map_err(|errors| {
+        errors
+            .iter()
+            .map(|e| format!("{} {}", e.field, e.reason))
+            .collect::<Vec<_>>()
+            .join("\n")
+    })
+}
+
+fn open_tun(name: &str, tun: &TunConfig) -> Result<OwnedFd, String> {
+    let (ip, prefix) = tun.ipv4.split_once('/').ok_or("tun.ipv4 has no prefix")?;
+    let ip: std::net::Ipv4Addr = ip.parse().map_err(|e| format!("tun.ipv4: {}", e))?;
+    let prefix: u32 = prefix.parse().map_err(|e| format!("tun.ipv4: {}", e))?;
+    let netmask = std::net::Ipv4Addr::from(u32::MAX.checked_shl(32 - prefix).unwrap_or(0));
+
+    let mut config = tun::Configuration::default();
+    config.tun_name(name).address(ip).netmask(netmask).mtu(tun.mtu).up();
+    let device = tun::create(&config).map_err(|e| format!("TUN_CREATE_FAILED: {}", e))?;
+    if let Some(ipv6) = &tun.ipv6 {
+        // The tun crate sets one address; the v6 one goes on with iproute2
+        let added = std::process::Command::new("ip").args(["-6", "addr", "add", ipv6, "dev", name]).status();
+        if !added.is_ok_and(|s| s.success()) {
+            eprintln!("CLI >> WARN: could not add {} to {}", ipv6, name);
+        }
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(device.into_raw_fd()) })
+}
+
+fn open_socketpair() -> Result<OwnedFd, String> {
+    let mut fds = [0; 2];
+    if unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_SEQPACKET, 0, fds.as_mut_ptr()) } != 0 {
+        return Err(format!("SOCKETPAIR_FAILED: {}", std::io::Error::last_os_error()));
+    }
+    let (engine, peer) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+    std::thread::spawn(move || print_frames(peer));
+    Ok(engine)
+}
+
+// The peer end stands in for the kernel: print every frame the engine writes to it
+fn print_frames(peer: OwnedFd) {
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let n = unsafe { libc::read(peer.as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+        if n <= 0 {
+            break;
+        }
+        let frame = &buf[..n as usize];
+        match etherparse::SlicedPacket::from_ip(frame).ok().and_then(|p| p.net) {
+            Some(etherparse::NetSlice::Ipv4(ip)) => eprintln!(
+                "CLI >> FRAME {} bytes {} -> {}",
+                frame.len(),
+                ip.header().source_addr(),
+                ip.header().destination_addr()
+            ),
+            Some(etherparse::NetSlice::Ipv6(ip)) => eprintln!(
+                "CLI >> FRAME {} bytes {} -> {}",
+                frame.len(),
+                ip.header().source_addr(),
+                ip.header().destination_addr()
+            ),
+            None => eprintln!("CLI >> FRAME {} bytes (not IP)", frame.len()),
+        }
+    }
+}
+
+// Ctrl-C or SIGTERM ends the session the same way the app's stopEngine does
+fn stop_on_signal(engine: Arc<Engine>) {
+    std::thread::spawn(move || {
+        let Ok(rt) = tokio::runtime::Builder::new_current_thread().enable_all().build() else { return };
+        rt.block_on(async {
+            let Ok(mut term) = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) else { return };
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = term.recv() => {}
+            }
+        });
+        engine.stop();
+    });
+}
+
+fn main() -> ExitCode {
+    let options = match parse_args() {
+        Ok(options) => options,
+        Err(e) => {
+            if !e.is_empty() {
+                eprintln!("{}", e);
+            }
+            eprintln!("{}", USAGE);
+            return ExitCode::from(2);
+        }
+    };
+    Engine::set_log_sink(|msg| eprintln!("{}", msg));
+
+    let config = match load_config(options.config.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("CLI >> CONFIG_REJECTED:\n{}", e);
+            return ExitCode::from(2);
+        }
+    };
+    let tun_config = config.tun.clone();
+    let engine = match Engine::new(config) {
+        Ok(engine) => Arc::new(engine),
+        Err(errors) => {
+            eprintln!("CLI >> CONFIG_REJECTED: {} errors", errors.len());
+            return ExitCode::from(2);
+        }
+    };
+
+    let fd = match options.tun.as_deref() {
+        Some(name) => open_tun(name, &tun_config),
+        None => open_socketpair(),
+    };
+    let fd = match fd {
+        Ok(fd) => fd,
+        Err(e) => {
+            eprintln!("CLI >> {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    if options.capture.is_some() {
+        // Unbounded in time; the ring size still caps memory
+        engine.start_capture(CaptureConfig { max_seconds: 0, ..CaptureConfig::default() });
+    }
+    stop_on_signal(engine.clone());
+    let status = if options.shield { engine.run_shield(fd.as_raw_fd()) } else { engine.run(fd.as_raw_fd()) };
+    println!("{}", serde_json::to_string_pretty(&engine.health()).unwrap_or_default());
+    if let Some(path) = options.capture.as_deref() {
+        match engine.stop_capture(Some(std::path::Path::new(path))) {
+            Ok(summary) => eprintln!("CLI >> CAPTURE {} packets -> {}", summary.packets, path),
+            Err(e) => eprintln!("CLI >> {}", e),
+        }
+    }
+    if status == Status::Error {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
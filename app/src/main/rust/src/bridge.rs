@@ -0,0 +1,140 @@
+use jni::errors::Error as JniError;
+use jni::objects::{GlobalRef, JClass, JLongArray, JStaticMethodID, JString};
+use jni::signature::{Primitive, ReturnType};
+use jni::sys::{jint, jstring, jvalue};
+use jni::{JNIEnv, JavaVM};
+use std::any::Any;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::OnceLock;
+
+// --- JNI BRIDGE ---
+// The only code that holds on to the JavaVM. Everything is resolved once in JNI_OnLoad and
+// kept in a OnceLock, so any thread can log without `static mut`. Entry points run through
+// `entry`, which turns bad arguments into IllegalArgumentException and panics into
+// RuntimeException instead of returning quietly or unwinding into the JVM.
+
+const CLASS: &str = "com/example/igy/IgyNetwork";
+const ILLEGAL_ARGUMENT: &str = "java/lang/IllegalArgumentException";
+const RUNTIME_EXCEPTION: &str = "java/lang/RuntimeException";
+// Local refs one log call can create before its frame is popped
+const LOG_FRAME: i32 = 4;
+
+static BRIDGE: OnceLock<Bridge> = OnceLock::new();
+
+struct Bridge {
+    vm: JavaVM,
+    class: GlobalRef,
+    native_log: JStaticMethodID,
+}
+
+#[derive(Debug)]
+pub enum BridgeError {
+    // Thrown to the caller as IllegalArgumentException
+    IllegalArgument(String),
+    Jni(JniError),
+}
+
+impl From<JniError> for BridgeError {
+    fn from(e: JniError) -> Self {
+        match e {
+            JniError::NullPtr(what) | JniError::NullDeref(what) => BridgeError::IllegalArgument(format!("NULL_ARGUMENT: {}", what)),
+            e => BridgeError::Jni(e),
+        }
+    }
+}
+
+/// Resolves the IgyNetwork class and `nativeLog` once. Without them logging is a no-op, but
+/// the library still loads.
+pub fn init(env: &mut JNIEnv) -> Result<jint, BridgeError> {
+    let vm = env.get_java_vm()?;
+    let found = env.find_class(CLASS).and_then(|class| {
+        let native_log = env.get_static_method_id(&class, "nativeLog", "(Ljava/lang/String;)V")?;
+        Ok((env.new_global_ref(class)?, native_log))
+    });
+    // A missing class leaves NoClassDefFoundError pending, which would fail System.loadLibrary
+    if found.is_err() && env.exception_check().unwrap_or(false) {
+        let _ = env.exception_clear();
+    }
+    if let Ok((class, native_log)) = found {
+        let _ = BRIDGE.set(Bridge { vm, class, native_log });
+    }
+    Ok(jni::sys::JNI_VERSION_1_6)
+}
+
+/// Sends `msg` to `IgyNetwork.nativeLog` from any thread.
+pub fn log(msg: &str) {
+    let Some(bridge) = BRIDGE.get() else { return };
+    // Engine threads stay attached until they exit; as daemons they never hold up VM shutdown
+    let Ok(mut env) = bridge.vm.attach_current_thread_as_daemon() else { return };
+    // A thread that never returns to Java never frees its local refs, so scope them
+    let _ = env.with_local_frame(LOG_FRAME, |env| -> Result<(), JniError> {
+        let msg = env.new_string(msg)?;
+        let class: &JClass = bridge.class.as_obj().into();
+        let args = [jvalue { l: msg.as_raw() }];
+        unsafe {
+            env.call_static_method_unchecked(class, bridge.native_log, ReturnType::Primitive(Primitive::Void), &args)?;
+        }
+        Ok(())
+    });
+    // Whatever nativeLog threw must not surface in an unrelated JNI call later on
+    if env.exception_check().unwrap_or(false) {
+        let _ = env.exception_clear();
+    }
+}
+
+/// Runs the body of an exported function. Errors and panics become Java exceptions and the
+/// function returns `fallback`, which Java ignores while the exception is pending.
+pub fn entry<'local, T, F>(env: &mut JNIEnv<'local>, fallback: T, body: F) -> T
+where
+    F: FnOnce(&mut JNIEnv<'local>) -> Result<T, BridgeError>,
+{
+    let (class, message) = match catch_unwind(AssertUnwindSafe(|| body(env))) {
+        Ok(Ok(value)) => return value,
+        Ok(Err(BridgeError::IllegalArgument(reason))) => (ILLEGAL_ARGUMENT, reason),
+        // Already pending; throwing again would replace the original
+        Ok(Err(BridgeError::Jni(JniError::JavaException))) => return fallback,
+        Ok(Err(BridgeError::Jni(e))) => (RUNTIME_EXCEPTION, format!("JNI_ERROR: {}", e)),
+        Err(panic) => (RUNTIME_EXCEPTION, format!("NATIVE_PANIC: {}", panic_message(panic.as_ref()))),
+    };
+    if env.exception_check().unwrap_or(false) {
+        let _ = env.exception_clear();
+    }
+    let _ = env.throw_new(class, message);
+    fallback
+}
+
+pub fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown")
+}
+
+pub fn string(env: &mut JNIEnv, value: &JString) -> Result<String, BridgeError> {
+    if value.is_null() {
+        return Err(BridgeError::IllegalArgument("NULL_ARGUMENT: String".to_string()));
+    }
+    Ok(env.get_string(value)?.into())
+}
+
+pub fn new_string(env: &mut JNIEnv, value: impl AsRef<str>) -> Result<jstring, BridgeError> {
+    Ok(env.new_string(value.as_ref())?.into_raw())
+}
+
+pub fn uids(env: &mut JNIEnv, array: &JLongArray) -> Result<Vec<u32>, BridgeError> {
+    if array.is_null() {
+        return Err(BridgeError::IllegalArgument("NULL_ARGUMENT: long[]".to_string()));
+    }
+    let mut raw = vec![0i64; env.get_array_length(array)? as usize];
+    env.get_long_array_region(array, 0, &mut raw)?;
+    checked_uids(&raw).map_err(BridgeError::IllegalArgument)
+}
+
+/// Android UIDs are unsigned 32-bit; anything else in the array is a caller bug.
+pub fn checked_uids(raw: &[i64]) -> Result<Vec<u32>, String> {
+    raw.iter()
+        .enumerate()
+        .map(|(i, uid)| u32::try_from(*uid).map_err(|_| format!("INVALID_UID: uids[{}]={}", i, uid)))
+        .collect()
+}
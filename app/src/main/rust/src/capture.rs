@@ -0,0 +1,232 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use crate::common::*;
+
+// --- PACKET CAPTURE: PCAPNG FROM THE PACKET PATH ---
+// While a capture runs, FilteredTun and the passive shield copy every frame they judge into
+// a ring buffer, tagged with direction, owning UID and verdict. Stopping writes the ring as
+// pcapng with LINKTYPE_RAW, so Wireshark opens it as plain IP and shows the tags as packet
+// comments. With no capture running the packet path pays one atomic load.
+
+const LINKTYPE_RAW: u16 = 101;
+const MAX_RING_BYTES: usize = 64 * 1024 * 1024;
+const MAX_SNAPLEN: usize = 65535;
+// Smallest snaplen that still holds an IPv6 header
+const MIN_SNAPLEN: usize = 40;
+
+const BLOCK_SHB: u32 = 0x0A0D_0D0A;
+const BLOCK_IDB: u32 = 0x0000_0001;
+const BLOCK_EPB: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+const OPT_END: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+const OPT_SHB_USERAPPL: u16 = 4;
+const OPT_IF_NAME: u16 = 2;
+const OPT_EPB_FLAGS: u16 = 2;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CaptureConfig {
+    // Ring size; the oldest packets make room for new ones
+    pub max_bytes: usize,
+    // Recording stops on its own after this long; 0 records until stopped
+    pub max_seconds: u64,
+    // Bytes kept of each packet
+    pub snaplen: usize,
+    // Only packets of these apps; empty records everything
+    pub uids: Vec<u32>,
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        CaptureConfig { max_bytes: 4 * 1024 * 1024, max_seconds: 300, snaplen: 1500, uids: Vec::new() }
+    }
+}
+
+impl CaptureConfig {
+    /// Parses `{"max_bytes":4194304,"max_seconds":300,"snaplen":1500,"uids":[10123]}`;
+    /// missing fields keep their defaults.
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        let config: CaptureConfig = serde_json::from_str(json).map_err(|e| format!("INVALID_CAPTURE_CONFIG: {}", e))?;
+        if !(1..=MAX_RING_BYTES).contains(&config.max_bytes) {
+            return Err(format!("MAX_BYTES_OUT_OF_RANGE: {}", config.max_bytes));
+        }
+        if !(MIN_SNAPLEN..=MAX_SNAPLEN).contains(&config.snaplen) {
+            return Err(format!("SNAPLEN_OUT_OF_RANGE: {}", config.snaplen));
+        }
+        Ok(config)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    // From the apps towards the network
+    Outbound,
+    // Back towards the apps
+    Inbound,
+}
+
+struct Record {
+    timestamp: Duration,
+    direction: Direction,
+    uid: Option<u32>,
+    verdict: &'static str,
+    orig_len: usize,
+    data: Vec<u8>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct CaptureSummary {
+    // Packets in the file
+    pub packets: usize,
+    pub bytes: usize,
+    // Pushed out of the ring by newer ones
+    pub evicted: u64,
+    // The time limit ended the recording before it was stopped
+    pub timed_out: bool,
+}
+
+pub struct Capture {
+    config: CaptureConfig,
+    started: Instant,
+    records: VecDeque<Record>,
+    bytes: usize,
+    evicted: u64,
+    timed_out: bool,
+}
+
+impl Capture {
+    pub fn new(config: CaptureConfig) -> Self {
+        Capture { config, started: Instant::now(), records: VecDeque::new(), bytes: 0, evicted: 0, timed_out: false }
+    }
+
+    /// Adds one packet seen at `now`. False once the time limit has passed.
+    pub fn push(&mut self, now: Instant, direction: Direction, uid: Option<u32>, verdict: &'static str, packet: &[u8]) -> bool {
+        let limit = self.config.max_seconds;
+        if limit > 0 && now.duration_since(self.started) >= Duration::from_secs(limit) {
+            self.timed_out = true;
+            return false;
+        }
+        if !self.config.uids.is_empty() && !uid.is_some_and(|uid| self.config.uids.contains(&uid)) {
+            return true;
+        }
+        let data = packet[..packet.len().min(self.config.snaplen)].to_vec();
+        self.bytes += data.len();
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        self.records.push_back(Record { timestamp, direction, uid, verdict, orig_len: packet.len(), data });
+        while self.bytes > self.config.max_bytes {
+            match self.records.pop_front() {
+                Some(old) => {
+                    self.bytes -= old.data.len();
+                    self.evicted += 1;
+                }
+                None => break,
+            }
+        }
+        true
+    }
+
+    pub fn summary(&self) -> CaptureSummary {
+        CaptureSummary { packets: self.records.len(), bytes: self.bytes, evicted: self.evicted, timed_out: self.timed_out }
+    }
+
+    /// Writes the ring as one pcapng section with a single LINKTYPE_RAW interface.
+    pub fn write_pcapng<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let mut shb = Vec::new();
+        shb.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        shb.extend_from_slice(&1u16.to_le_bytes());
+        shb.extend_from_slice(&0u16.to_le_bytes());
+        // Section length not given
+        shb.extend_from_slice(&(-1i64).to_le_bytes());
+        push_option(&mut shb, OPT_SHB_USERAPPL, b"igy_core");
+        push_option(&mut shb, OPT_END, &[]);
+        write_block(out, BLOCK_SHB, &shb)?;
+
+        let mut idb = Vec::new();
+        idb.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        idb.extend_from_slice(&0u16.to_le_bytes());
+        idb.extend_from_slice(&(self.config.snaplen as u32).to_le_bytes());
+        push_option(&mut idb, OPT_IF_NAME, b"tun");
+        push_option(&mut idb, OPT_END, &[]);
+        write_block(out, BLOCK_IDB, &idb)?;
+
+        for record in &self.records {
+            let mut epb = Vec::with_capacity(record.data.len() + 64);
+            // Interface 0, timestamps in the default microsecond resolution
+            epb.extend_from_slice(&0u32.to_le_bytes());
+            let micros = record.timestamp.as_micros() as u64;
+            epb.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+            epb.extend_from_slice(&(micros as u32).to_le_bytes());
+            epb.extend_from_slice(&(record.data.len() as u32).to_le_bytes());
+            epb.extend_from_slice(&(record.orig_len as u32).to_le_bytes());
+            epb.extend_from_slice(&record.data);
+            pad(&mut epb);
+            let uid = record.uid.map(|u| u.to_string()).unwrap_or_else(|| "?".to_string());
+            push_option(&mut epb, OPT_COMMENT, format!("uid={} verdict={}", uid, record.verdict).as_bytes());
+            // Bits 0-1 of epb_flags: 1 inbound, 2 outbound
+            let flags: u32 = match record.direction {
+                Direction::Inbound => 1,
+                Direction::Outbound => 2,
+            };
+            push_option(&mut epb, OPT_EPB_FLAGS, &flags.to_le_bytes());
+            push_option(&mut epb, OPT_END, &[]);
+            write_block(out, BLOCK_EPB, &epb)?;
+        }
+        out.flush()
+    }
+}
+
+fn pad(buf: &mut Vec<u8>) {
+    buf.resize(buf.len().next_multiple_of(4), 0);
+}
+
+fn push_option(buf: &mut Vec<u8>, code: u16, value: &[u8]) {
+    buf.extend_from_slice(&code.to_le_bytes());
+    buf.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    buf.extend_from_slice(value);
+    pad(buf);
+}
+
+fn write_block<W: Write>(out: &mut W, kind: u32, body: &[u8]) -> io::Result<()> {
+    // Type and both length fields wrap the body
+    let total = (body.len() + 12) as u32;
+    out.write_all(&kind.to_le_bytes())?;
+    out.write_all(&total.to_le_bytes())?;
+    out.write_all(body)?;
+    out.write_all(&total.to_le_bytes())
+}
+
+/// Starts a new recording, discarding one still in progress.
+pub fn start(config: CaptureConfig) {
+    if let Ok(mut capture) = CAPTURE.lock() {
+        *capture = Some(Capture::new(config));
+        CAPTURE_ACTIVE.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Ends the recording and hands it over; `None` if none was started.
+pub fn stop() -> Option<Capture> {
+    CAPTURE_ACTIVE.store(false, Ordering::SeqCst);
+    CAPTURE.lock().ok().and_then(|mut capture| capture.take())
+}
+
+pub fn active() -> bool {
+    CAPTURE_ACTIVE.load(Ordering::Relaxed)
+}
+
+/// Hot-path hook: records `packet` if a capture is running.
+pub fn record(direction: Direction, uid: Option<u32>, verdict: &'static str, packet: &[u8]) {
+    if !active() {
+        return;
+    }
+    let Ok(mut guard) = CAPTURE.lock() else { return };
+    let Some(capture) = guard.as_mut() else { return };
+    if !capture.push(Instant::now(), direction, uid, verdict, packet) {
+        // Keep what was recorded for stop(), just stop adding to it
+        CAPTURE_ACTIVE.store(false, Ordering::SeqCst);
+        crate::log_to_java("VPN >> CAPTURE_TIME_LIMIT_REACHED");
+    }
+}
@@ -0,0 +1,182 @@
+use std::net::IpAddr;
+
+// --- CIDR TABLE: PATH-COMPRESSED BINARY RADIX TREE ---
+// Longest-prefix match over IPv4 and IPv6. Nodes live in one Vec and link by index; runs of
+// single-child nodes are collapsed, so a table of N prefixes has at most 2N nodes.
+
+const NONE: u32 = u32::MAX;
+
+#[derive(Debug)]
+struct Node<T> {
+    // Prefix bits left-aligned in a u128 (IPv4 uses the top 32 bits)
+    key: u128,
+    len: u8,
+    value: Option<T>,
+    child: [u32; 2],
+}
+
+#[derive(Debug)]
+struct Trie<T> {
+    nodes: Vec<Node<T>>,
+    width: u8,
+}
+
+fn bit_at(key: u128, pos: u8) -> usize {
+    ((key >> (127 - pos as u32)) & 1) as usize
+}
+
+fn mask(key: u128, len: u8) -> u128 {
+    if len == 0 { 0 } else { key & (u128::MAX << (128 - len as u32)) }
+}
+
+fn common_len(a: u128, b: u128, max: u8) -> u8 {
+    ((a ^ b).leading_zeros() as u8).min(max)
+}
+
+impl<T: Copy> Trie<T> {
+    fn new(width: u8) -> Self {
+        Trie { nodes: vec![Node { key: 0, len: 0, value: None, child: [NONE; 2] }], width }
+    }
+
+    fn push(&mut self, key: u128, len: u8, value: Option<T>) -> u32 {
+        self.nodes.push(Node { key: mask(key, len), len, value, child: [NONE; 2] });
+        (self.nodes.len() - 1) as u32
+    }
+
+    fn insert(&mut self, key: u128, len: u8, value: T) {
+        let key = mask(key, len);
+        let mut idx = 0usize;
+        loop {
+            if self.nodes[idx].len == len {
+                self.nodes[idx].value = Some(value);
+                return;
+            }
+            let bit = bit_at(key, self.nodes[idx].len);
+            let c = self.nodes[idx].child[bit];
+            if c == NONE {
+                let leaf = self.push(key, len, Some(value));
+                self.nodes[idx].child[bit] = leaf;
+                return;
+            }
+            let (child_key, child_len) = (self.nodes[c as usize].key, self.nodes[c as usize].len);
+            let common = common_len(key, child_key, len.min(child_len));
+            if common == child_len {
+                idx = c as usize;
+                continue;
+            }
+            let split = if common == len {
+                // New prefix sits between this node and the child
+                self.push(key, len, Some(value))
+            } else {
+                let mid = self.push(key, common, None);
+                let leaf = self.push(key, len, Some(value));
+                self.nodes[mid as usize].child[bit_at(key, common)] = leaf;
+                mid
+            };
+            self.nodes[split as usize].child[bit_at(child_key, common)] = c;
+            self.nodes[idx].child[bit] = split;
+            return;
+        }
+    }
+
+    fn lookup(&self, key: u128) -> Option<T> {
+        let mut idx = 0usize;
+        let mut best = self.nodes[0].value;
+        loop {
+            let node = &self.nodes[idx];
+            if node.len >= self.width {
+                return best;
+            }
+            let c = node.child[bit_at(key, node.len)];
+            if c == NONE {
+                return best;
+            }
+            let child = &self.nodes[c as usize];
+            if common_len(key, child.key, child.len) < child.len {
+                return best;
+            }
+            if child.value.is_some() {
+                best = child.value;
+            }
+            idx = c as usize;
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct CidrTable<T> {
+    v4: Trie<T>,
+    v6: Trie<T>,
+}
+
+impl<T: Copy> Default for CidrTable<T> {
+    fn default() -> Self {
+        CidrTable { v4: Trie::new(32), v6: Trie::new(128) }
+    }
+}
+
+impl<T: Copy> CidrTable<T> {
+    /// Accepts `a.b.c.d/n`, `x::y/n` or a bare address (host route).
+    pub fn insert_str(&mut self, cidr: &str, value: T) -> Result<(), String> {
+        let (ip, len) = parse_cidr(cidr)?;
+        self.insert(ip, len, value);
+        Ok(())
+    }
+
+    pub fn insert(&mut self, ip: IpAddr, len: u8, value: T) {
+        match ip {
+            IpAddr::V4(v4) => self.v4.insert((u32::from(v4) as u128) << 96, len, value),
+            IpAddr::V6(v6) => self.v6.insert(u128::from(v6), len, value),
+        }
+    }
+
+    /// Value of the most specific prefix containing `ip`.
+    pub fn lookup(&self, ip: IpAddr) -> Option<T> {
+        match ip {
+            IpAddr::V4(v4) => self.v4.lookup((u32::from(v4) as u128) << 96),
+            IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+                Some(v4) => self.v4.lookup((u32::from(v4) as u128) << 96),
+                None => self.v6.lookup(u128::from(v6)),
+            },
+        }
+    }
+}
+
+pub fn parse_cidr(cidr: &str) -> Result<(IpAddr, u8), String> {
+    let cidr = cidr.trim();
+    let (addr, len) = match cidr.split_once('/') {
+        Some((a, l)) => (a, Some(l)),
+        None => (cidr, None),
+    };
+    let ip: IpAddr = addr.parse().map_err(|_| format!("INVALID_CIDR: {}", cidr))?;
+    let max = if ip.is_ipv4() { 32 } else { 128 };
+    let len = match len {
+        Some(l) => l.parse::<u8>().ok().filter(|l| *l <= max).ok_or_else(|| format!("INVALID_PREFIX_LEN: {}", cidr))?,
+        None => max,
+    };
+    Ok((ip, len))
+}
+
+/// Whether two prefixes share any address. Prefixes either nest or are disjoint, so it is
+/// enough to check each start against the other.
+pub fn overlaps(a: (IpAddr, u8), b: (IpAddr, u8)) -> bool {
+    let contains = |outer: (IpAddr, u8), ip: IpAddr| {
+        let mut table = CidrTable::default();
+        table.insert(outer.0, outer.1, ());
+        table.lookup(ip).is_some()
+    };
+    contains(a, b.0) || contains(b, a.0)
+}
+
+/// Ranges that never make sense through a remote proxy.
+pub const LAN_RANGES: &[&str] = &[
+    "10.0.0.0/8",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "169.254.0.0/16",
+    "224.0.0.0/4",
+    "255.255.255.255/32",
+    "fc00::/7",
+    "fe80::/10",
+    "ff00::/8",
+];
@@ -1,32 +1,116 @@
+use std::collections::HashMap;
 use std::sync::atomic::{AtomicU64, AtomicBool, AtomicU16, AtomicU8};
-use std::sync::RwLock;
-use tokio::runtime::Runtime;
+use std::sync::{Arc, Mutex, RwLock};
+use crate::capture::Capture;
+use crate::cidr::CidrTable;
+use crate::config::EngineConfig;
+use crate::diagnostics::TunnelReport;
+use crate::energy::RadioTracker;
+use crate::geoip::GeoIpDb;
+use crate::health::SessionHealth;
+use crate::routing::RoutingPolicy;
+use crate::runtime::RuntimeConfig;
+use shadowsocks::config::ServerConfig;
+use shadowsocks_service::acl::AccessControl;
+use tokio::runtime::Handle;
+use tun2proxy::CancellationToken;
+use serde::Deserialize;
 use zeroize::{Zeroize, ZeroizeOnDrop};
 
 pub static TCP_COUNT: AtomicU64 = AtomicU64::new(0);
 pub static UDP_COUNT: AtomicU64 = AtomicU64::new(0);
 pub static OTHER_COUNT: AtomicU64 = AtomicU64::new(0);
 pub static BYTES_PROCESSED: AtomicU64 = AtomicU64::new(0);
-pub static STEALTH_MODE: AtomicBool = AtomicBool::new(false);
 pub static PROXY_PORT: AtomicU16 = AtomicU16::new(10808);
 
 // Health Status: 0=STOPPED, 1=STARTING, 2=RUNNING, 3=ERROR
 pub static CORE_STATUS: AtomicU8 = AtomicU8::new(0);
 
-#[derive(Zeroize, ZeroizeOnDrop, Default, Clone)]
+// UDP path of the running session, see udp_relay::UdpMode
+pub static UDP_MODE: AtomicU8 = AtomicU8::new(0);
+
+// Kill switch: drop everything but the allowlist while the upstream is unhealthy
+pub static KILL_SWITCH: AtomicBool = AtomicBool::new(false);
+pub static UPSTREAM_UP: AtomicBool = AtomicBool::new(false);
+pub static LEAK_BLOCKED_PACKETS: AtomicU64 = AtomicU64::new(0);
+pub static LEAK_BLOCKED_BYTES: AtomicU64 = AtomicU64::new(0);
+pub static LEAK_ALLOWED_PACKETS: AtomicU64 = AtomicU64::new(0);
+pub static LEAK_TRIPS: AtomicU64 = AtomicU64::new(0);
+// RST / ICMP unreachable replies written for blocked flows
+pub static REJECTS_SENT: AtomicU64 = AtomicU64::new(0);
+// The subset that refused a connect the routing policy blocked; each spares the SYN retries
+pub static SYN_REJECTS_SENT: AtomicU64 = AtomicU64::new(0);
+// TUN reads that were not a whole IP packet
+pub static MALFORMED_FRAMES: AtomicU64 = AtomicU64::new(0);
+// Inbound packets discarded before reaching an app, and those that answered no known flow
+pub static INBOUND_DROPPED: AtomicU64 = AtomicU64::new(0);
+pub static INBOUND_UNSOLICITED: AtomicU64 = AtomicU64::new(0);
+// Bumped by every start_vpn_loop so leftovers of an earlier session can tell they are stale
+pub static VPN_SESSION: AtomicU64 = AtomicU64::new(0);
+// Cancels the session in progress; see Engine::stop
+pub static SESSION_STOP: Mutex<Option<CancellationToken>> = Mutex::new(None);
+// Packet capture in progress; the flag spares the packet path the lock when there is none
+pub static CAPTURE_ACTIVE: AtomicBool = AtomicBool::new(false);
+pub static CAPTURE: Mutex<Option<Capture>> = Mutex::new(None);
+// Bytes the VPN path passed each way, for the health throughput rates
+pub static TX_BYTES: AtomicU64 = AtomicU64::new(0);
+pub static RX_BYTES: AtomicU64 = AtomicU64::new(0);
+// Flows the routing table currently tracks
+pub static ACTIVE_FLOWS: AtomicU64 = AtomicU64::new(0);
+// DNS packets the apps sent to port 53, and the replies that came back
+pub static DNS_QUERIES: AtomicU64 = AtomicU64::new(0);
+pub static DNS_ANSWERS: AtomicU64 = AtomicU64::new(0);
+pub static LOCKDOWN_ALLOWED: AtomicU64 = AtomicU64::new(0);
+pub static LOCKDOWN_DROPPED: AtomicU64 = AtomicU64::new(0);
+pub static LOCKDOWN_BLOCKED_FLOWS: AtomicU64 = AtomicU64::new(0);
+// What the policy kept off the network, for the energy estimate
+pub static BLOCKED_FLOWS: AtomicU64 = AtomicU64::new(0);
+pub static BLOCKED_PACKETS: AtomicU64 = AtomicU64::new(0);
+pub static BLOCKED_BYTES: AtomicU64 = AtomicU64::new(0);
+pub static RADIO_WAKEUPS_AVOIDED: AtomicU64 = AtomicU64::new(0);
+pub static RADIO: RadioTracker = RadioTracker::new();
+
+#[derive(Zeroize, ZeroizeOnDrop, Default, Clone, PartialEq, Deserialize)]
+#[serde(transparent)]
 pub struct SecureKey {
     pub key: String,
 }
 
+// Keys end up in configs that get logged on failure; never print them
+impl std::fmt::Debug for SecureKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(if self.key.is_empty() { "SecureKey(empty)" } else { "SecureKey(***)" })
+    }
+}
+
 lazy_static::lazy_static! {
-    pub static ref OUTLINE_KEY: RwLock<SecureKey> = RwLock::new(SecureKey::default());
+    // Last applied engine config; the globals below that it covers are derived from it
+    pub static ref ENGINE_CONFIG: RwLock<Arc<EngineConfig>> = RwLock::new(Arc::default());
     pub static ref ALLOWED_DOMAINS: RwLock<Vec<String>> = RwLock::new(Vec::new());
     pub static ref ALLOWED_UIDS: RwLock<Vec<u32>> = RwLock::new(Vec::new());
-    pub static ref TOKIO_RT: Runtime = tokio::runtime::Builder::new_multi_thread()
-        .worker_threads(2)
-        .enable_all()
-        .build()
-        .expect("Failed to create Tokio runtime");
+    // Multi-server pool fed by subscriptions; ss-local balances across it
+    pub static ref SERVER_POOL: RwLock<Vec<ServerConfig>> = RwLock::new(Vec::new());
+    pub static ref ROUTING_POLICY: RwLock<Arc<RoutingPolicy>> = RwLock::new(Arc::default());
+    // Validated bypass/proxy lists handed to every ss-local instance
+    pub static ref SS_ACL: RwLock<Option<AccessControl>> = RwLock::new(None);
+    pub static ref KILL_SWITCH_ALLOW: RwLock<Arc<CidrTable<()>>> = RwLock::new(Arc::default());
+    // Offline country database for GeoIP rules, loaded from a file the app provides
+    pub static ref GEOIP_DB: RwLock<Option<Arc<GeoIpDb>>> = RwLock::new(None);
+    // Bytes delivered back to each app through the tunnel
+    pub static ref UID_RX_BYTES: Mutex<HashMap<u32, u64>> = Mutex::new(HashMap::new());
+    // Applied to the runtime of the next engine session
+    pub static ref RUNTIME_CONFIG: RwLock<RuntimeConfig> = RwLock::new(RuntimeConfig::default());
+    // Runtime of the session in progress, tagged with its session number
+    pub static ref SESSION_RUNTIME: RwLock<Option<(u64, Handle)>> = RwLock::new(None);
+    // Uptime, upstream, resolver and error history for getCoreHealth
+    pub static ref HEALTH: Mutex<SessionHealth> = Mutex::new(SessionHealth::default());
+    // Parent of every diagnostics run; cancelDiagnostics replaces it
+    pub static ref DIAGNOSTICS_STOP: Mutex<CancellationToken> = Mutex::new(CancellationToken::new());
+    // Latest tunnel probe of each server, keyed by its address, for the server picker
+    pub static ref TUNNEL_RESULTS: Mutex<HashMap<String, TunnelReport>> = Mutex::new(HashMap::new());
 }
 
 pub static BANDWIDTH_LIMIT: AtomicU64 = AtomicU64::new(0);
+
+// Bumped whenever the routing policy or the FOCUS allowlist changes
+pub static POLICY_GENERATION: AtomicU64 = AtomicU64::new(0);
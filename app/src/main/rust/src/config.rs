@@ -0,0 +1,297 @@
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, RwLockWriteGuard};
+use crate::common::*;
+use crate::routing::{install_policy, RoutingPolicy};
+use crate::runtime::RuntimeConfig;
+use crate::upstream::Upstream;
+
+// --- ENGINE CONFIG: ONE VERSIONED DOCUMENT ---
+// Everything Kotlin can tune is one document, validated as a whole and applied under one
+// lock. Packet-path state (focus lists, policy, kill switch, bandwidth cap) changes live;
+// what a session only reads when it starts (key, stealth, ports, interface, runtime) comes
+// from a single snapshot, so a session never mixes old and new values.
+
+pub const CONFIG_VERSION: u32 = 1;
+const MAX_BANDWIDTH_MBPS: u32 = 10_000;
+// Changing these only takes effect on the next session
+const SESSION_FIELDS: [&str; 6] = ["key", "stealth", "proxy_port", "udp_gateway", "tun", "runtime"];
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KillSwitchConfig {
+    pub enabled: bool,
+    // CIDRs still reachable (directly) while the upstream is down
+    pub allow: Vec<String>,
+    // `host:port` the health check connects to through the proxy; None is the tunnel's DNS server
+    pub probe: Option<String>,
+}
+
+/// How `IgyVpnService` sets up the interface; its builder reads this through `getTunConfig`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TunConfig {
+    pub mtu: u16,
+    // Interface address with prefix, e.g. `10.0.0.1/24`
+    pub ipv4: String,
+    // None keeps the tunnel IPv4-only
+    pub ipv6: Option<String>,
+    // Fake addresses tun2proxy's virtual DNS hands out; must not overlap the interface
+    pub virtual_dns_pool: String,
+}
+
+impl Default for TunConfig {
+    fn default() -> Self {
+        TunConfig {
+            mtu: 1280,
+            ipv4: "10.0.0.1/24".to_string(),
+            ipv6: Some("fd00:1::1/64".to_string()),
+            virtual_dns_pool: "198.18.0.0/15".to_string(),
+        }
+    }
+}
+
+impl TunConfig {
+    fn validate(&self, fail: &mut impl FnMut(String, String)) {
+        // IPv6 links may not go below 1280
+        let min_mtu = if self.ipv6.is_some() { 1280 } else { 576 };
+        if self.mtu < min_mtu {
+            fail("tun.mtu".to_string(), format!("MTU_TOO_SMALL: {}", self.mtu));
+        }
+        let ipv4 = match crate::cidr::parse_cidr(&self.ipv4) {
+            Ok((ip, len)) if ip.is_ipv4() => Some((ip, len)),
+            Ok(_) => {
+                fail("tun.ipv4".to_string(), format!("NOT_IPV4: {}", self.ipv4));
+                None
+            }
+            Err(e) => {
+                fail("tun.ipv4".to_string(), e);
+                None
+            }
+        };
+        if let Some(ipv6) = &self.ipv6 {
+            match crate::cidr::parse_cidr(ipv6) {
+                Ok((ip, _)) if ip.is_ipv6() => {}
+                Ok(_) => fail("tun.ipv6".to_string(), format!("NOT_IPV6: {}", ipv6)),
+                Err(e) => fail("tun.ipv6".to_string(), e),
+            }
+        }
+        match crate::cidr::parse_cidr(&self.virtual_dns_pool) {
+            Ok(pool) => {
+                if ipv4.is_some_and(|tun| crate::cidr::overlaps(tun, pool)) {
+                    fail("tun.virtual_dns_pool".to_string(), format!("OVERLAPS_TUN_SUBNET: {}", self.virtual_dns_pool));
+                }
+            }
+            Err(e) => fail("tun.virtual_dns_pool".to_string(), e),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EngineConfig {
+    pub version: u32,
+    pub key: SecureKey,
+    pub stealth: bool,
+    pub allowed_domains: Vec<String>,
+    pub allowed_uids: Vec<u32>,
+    pub bandwidth_limit_mbps: u32,
+    // 0 lets ss-local pick a free port
+    pub proxy_port: u16,
+    // `ip:port` of a udpgw server (badvpn framing, e.g. tun2proxy's udpgw-server) reachable
+    // through the proxy. UDP goes over TCP to it when the relay probe fails. Nothing here
+    // provisions one: it has to run next to the server, or UDP is unavailable on such servers
+    pub udp_gateway: Option<String>,
+    pub kill_switch: KillSwitchConfig,
+    pub tun: TunConfig,
+    // Same document `setRoutingPolicy` takes; absent means the default policy
+    pub routing: Option<serde_json::Value>,
+    pub runtime: RuntimeConfig,
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        EngineConfig {
+            version: CONFIG_VERSION,
+            key: SecureKey::default(),
+            stealth: false,
+            allowed_domains: Vec::new(),
+            allowed_uids: Vec::new(),
+            bandwidth_limit_mbps: 0,
+            proxy_port: 0,
+            udp_gateway: None,
+            kill_switch: KillSwitchConfig::default(),
+            tun: TunConfig::default(),
+            routing: None,
+            runtime: RuntimeConfig::default(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ConfigError {
+    // Path of the offending value, e.g. `allowed_domains[2]`; empty if the JSON itself is bad
+    pub field: String,
+    pub reason: String,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ApplyReport {
+    pub version: u32,
+    pub changed: Vec<&'static str>,
+    // A session is running and some change only applies to the next one
+    pub restart_required: bool,
+}
+
+impl EngineConfig {
+    /// Parses and validates a whole document. Fields left out keep their defaults.
+    pub fn from_json(json: &str) -> Result<Self, Vec<ConfigError>> {
+        let config: EngineConfig = serde_json::from_str(json).map_err(|e| {
+            vec![ConfigError { field: String::new(), reason: format!("INVALID_CONFIG: {}", e) }]
+        })?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Reports every invalid value rather than stopping at the first one.
+    pub fn validate(&self) -> Result<(), Vec<ConfigError>> {
+        let mut errors = Vec::new();
+        let mut fail = |field: String, reason: String| errors.push(ConfigError { field, reason });
+
+        if self.version == 0 || self.version > CONFIG_VERSION {
+            fail("version".to_string(), format!("UNSUPPORTED_VERSION: {}", self.version));
+        }
+        if !self.key.key.trim().is_empty() {
+            if let Err(e) = Upstream::from_url(&self.key.key) {
+                fail("key".to_string(), e);
+            }
+        }
+        for (i, domain) in self.allowed_domains.iter().enumerate() {
+            let domain = domain.trim();
+            if domain.is_empty() || !domain.is_ascii() || domain.contains(|c: char| c.is_ascii_whitespace() || c == ',') {
+                fail(format!("allowed_domains[{}]", i), format!("INVALID_DOMAIN: {}", domain));
+            }
+        }
+        if self.bandwidth_limit_mbps > MAX_BANDWIDTH_MBPS {
+            fail("bandwidth_limit_mbps".to_string(), format!("OUT_OF_RANGE: {}", self.bandwidth_limit_mbps));
+        }
+        if self.proxy_port != 0 && self.proxy_port < 1024 {
+            fail("proxy_port".to_string(), format!("PRIVILEGED_PORT: {}", self.proxy_port));
+        }
+        if let Some(gateway) = &self.udp_gateway {
+            if gateway.trim().parse::<SocketAddr>().is_err() {
+                fail("udp_gateway".to_string(), format!("INVALID_UDP_GATEWAY: {}", gateway));
+            }
+        }
+        for (i, cidr) in self.kill_switch.allow.iter().enumerate() {
+            if let Err(e) = crate::cidr::parse_cidr(cidr.trim()) {
+                fail(format!("kill_switch.allow[{}]", i), e);
+            }
+        }
+        if let Some(probe) = &self.kill_switch.probe {
+            if let Err(e) = crate::diagnostics::parse_target(probe, 443) {
+                fail("kill_switch.probe".to_string(), e);
+            }
+        }
+        self.tun.validate(&mut fail);
+        if let Some(routing) = &self.routing {
+            if let Err(e) = RoutingPolicy::from_json(&routing.to_string()) {
+                fail("routing".to_string(), e);
+            }
+        }
+        if let Err(e) = self.runtime.validate() {
+            fail("runtime".to_string(), e);
+        }
+
+        if errors.is_empty() { Ok(()) } else { Err(errors) }
+    }
+
+    pub fn udp_gateway_addr(&self) -> Option<SocketAddr> {
+        self.udp_gateway.as_deref().and_then(|g| g.trim().parse().ok())
+    }
+}
+
+/// The config as last applied. Sessions take this once when they start.
+pub fn snapshot() -> Arc<EngineConfig> {
+    ENGINE_CONFIG.read().map(|c| c.clone()).unwrap_or_default()
+}
+
+/// Replaces the whole config, applying only what differs from the current one.
+pub fn apply(next: EngineConfig) -> Result<ApplyReport, Vec<ConfigError>> {
+    next.validate()?;
+    let mut current = ENGINE_CONFIG.write().unwrap_or_else(|e| e.into_inner());
+    Ok(apply_locked(&mut current, next))
+}
+
+/// Edits one part of the current config; what the single-value setters go through.
+pub fn update<F: FnOnce(&mut EngineConfig)>(edit: F) -> Result<ApplyReport, Vec<ConfigError>> {
+    let mut current = ENGINE_CONFIG.write().unwrap_or_else(|e| e.into_inner());
+    let mut next = (**current).clone();
+    edit(&mut next);
+    next.validate()?;
+    Ok(apply_locked(&mut current, next))
+}
+
+fn apply_locked(current: &mut RwLockWriteGuard<'_, Arc<EngineConfig>>, next: EngineConfig) -> ApplyReport {
+    let old = current.clone();
+    let mut changed = Vec::new();
+
+    if next.allowed_domains != old.allowed_domains {
+        let domains = next.allowed_domains.iter().map(|d| d.trim().to_string()).collect();
+        if let Ok(mut allowed) = ALLOWED_DOMAINS.write() {
+            *allowed = domains;
+        }
+        changed.push("allowed_domains");
+    }
+    if next.allowed_uids != old.allowed_uids {
+        if let Ok(mut allowed) = ALLOWED_UIDS.write() {
+            *allowed = next.allowed_uids.clone();
+        }
+        POLICY_GENERATION.fetch_add(1, Ordering::SeqCst);
+        changed.push("allowed_uids");
+    }
+    if next.bandwidth_limit_mbps != old.bandwidth_limit_mbps {
+        BANDWIDTH_LIMIT.store(next.bandwidth_limit_mbps as u64 * 1024 * 1024 / 8, Ordering::Relaxed);
+        changed.push("bandwidth_limit_mbps");
+    }
+    if next.kill_switch != old.kill_switch {
+        // Validated above, so this cannot fail
+        let _ = crate::killswitch::configure(next.kill_switch.enabled, &next.kill_switch.allow.join(","));
+        changed.push("kill_switch");
+    }
+    if next.routing != old.routing {
+        let policy = next
+            .routing
+            .as_ref()
+            .and_then(|r| RoutingPolicy::from_json(&r.to_string()).ok())
+            .unwrap_or_default();
+        install_policy(policy);
+        changed.push("routing");
+    }
+    if next.runtime != old.runtime {
+        crate::runtime::configure(next.runtime.clone());
+        changed.push("runtime");
+    }
+    if next.key != old.key {
+        changed.push("key");
+    }
+    if next.stealth != old.stealth {
+        changed.push("stealth");
+    }
+    if next.proxy_port != old.proxy_port {
+        changed.push("proxy_port");
+    }
+    if next.udp_gateway != old.udp_gateway {
+        changed.push("udp_gateway");
+    }
+    if next.tun != old.tun {
+        changed.push("tun");
+    }
+
+    let running = CORE_STATUS.load(Ordering::SeqCst) != 0;
+    let restart_required = running && changed.iter().any(|f| SESSION_FIELDS.contains(f));
+    let version = next.version;
+    **current = Arc::new(next);
+    ApplyReport { version, changed, restart_required }
+}
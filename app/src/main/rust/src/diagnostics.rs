@@ -0,0 +1,799 @@
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tun2proxy::CancellationToken;
+use crate::common::*;
+use crate::udp_relay::{socks5_connect, socks5_handshake};
+use crate::upstream::ProxyAuth;
+
+// --- DIAGNOSTICS: CONNECTIVITY MEASURED ON DEMAND ---
+// Each probe is async, bounded by its own timeout and abandoned as soon as the run is
+// cancelled, so a dead network costs the caller at most one timeout. Results are plain
+// numbers per probe; a probe that failed says why instead of turning into a sentinel
+// value. The app process is outside the VPN, so these measure the direct path; only the
+// tunnel probe goes through the upstream, by way of the session's local SOCKS5 proxy.
+
+pub const DIAGNOSTICS_VERSION: u32 = 1;
+const MAX_COUNT: u32 = 100;
+const MAX_TIMEOUT_MS: u64 = 30_000;
+const MAX_THROUGHPUT_BYTES: u64 = 256 * 1024 * 1024;
+// Bytes read per throughput chunk
+const READ_CHUNK: usize = 64 * 1024;
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiagnosticsConfig {
+    // Host, address or URL for the connect and TLS probes, see `parse_target`; empty skips both
+    pub target: String,
+    // Connect attempts
+    pub count: u32,
+    // Per attempt, and per step of the other probes
+    pub timeout_ms: u64,
+    // Pause between connect attempts
+    pub interval_ms: u64,
+    pub dns: Option<DnsProbe>,
+    pub tls: Option<TlsProbe>,
+    pub throughput: Option<ThroughputProbe>,
+    pub tunnel: Option<TunnelProbe>,
+}
+
+impl Default for DiagnosticsConfig {
+    fn default() -> Self {
+        DiagnosticsConfig {
+            target: String::new(),
+            count: 5,
+            timeout_ms: 1500,
+            interval_ms: 200,
+            dns: None,
+            tls: None,
+            throughput: None,
+            tunnel: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DnsProbe {
+    pub name: String,
+    // `ip:port` of a resolver to ask directly; the system resolver otherwise
+    #[serde(default)]
+    pub server: Option<String>,
+    // Record asked from `server`: `A` or `AAAA`
+    #[serde(default = "default_record")]
+    pub record: String,
+}
+
+fn default_record() -> String {
+    "A".to_string()
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsProbe {
+    // SNI; the target's host when missing
+    #[serde(default)]
+    pub server_name: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ThroughputProbe {
+    // `host:port` that sends data once connected (and sent `request`, if given)
+    pub endpoint: String,
+    #[serde(default)]
+    pub request: Option<String>,
+    #[serde(default = "default_max_bytes")]
+    pub max_bytes: u64,
+    #[serde(default = "default_max_ms")]
+    pub max_ms: u64,
+}
+
+fn default_max_bytes() -> u64 {
+    8 * 1024 * 1024
+}
+
+fn default_max_ms() -> u64 {
+    5000
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TunnelProbe {
+    // SOCKS5 proxy to go through; the running session's ss-local when missing
+    pub proxy: Option<String>,
+    // RFC 1929 credentials for an explicit `proxy`; the session's ss-local takes none
+    pub auth: Option<ProxyAuth>,
+    // `host:port` asked of the proxy; names are resolved on the far side
+    pub target: String,
+    // Sent once connected, the first byte back is timed; a GET of /generate_204 by default
+    pub request: Option<String>,
+    // Round trips through the tunnel
+    pub count: u32,
+    // Download through the proxy; `endpoint` is then also resolved on the far side
+    pub download: Option<ThroughputProbe>,
+}
+
+impl Default for TunnelProbe {
+    fn default() -> Self {
+        TunnelProbe { proxy: None, auth: None, target: "www.gstatic.com:80".to_string(), request: None, count: 3, download: None }
+    }
+}
+
+impl DiagnosticsConfig {
+    /// Parses `{"target":"1.1.1.1:443","count":10,"dns":{"name":"example.com"},"tls":{}}`.
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        let config: DiagnosticsConfig = serde_json::from_str(json).map_err(|e| format!("INVALID_DIAGNOSTICS_CONFIG: {}", e))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if !(1..=MAX_COUNT).contains(&self.count) {
+            return Err(format!("COUNT_OUT_OF_RANGE: {}", self.count));
+        }
+        if !(1..=MAX_TIMEOUT_MS).contains(&self.timeout_ms) || self.interval_ms > MAX_TIMEOUT_MS {
+            return Err(format!("TIMEOUT_OUT_OF_RANGE: {}", self.timeout_ms));
+        }
+        if self.tls.is_some() && self.target.is_empty() {
+            return Err("TLS_NEEDS_TARGET".to_string());
+        }
+        if !self.target.is_empty() {
+            parse_target(&self.target, 80)?;
+        }
+        if let Some(dns) = &self.dns {
+            if dns.name.is_empty() {
+                return Err("DNS_NAME_EMPTY".to_string());
+            }
+            if !matches!(dns.record.as_str(), "A" | "AAAA") {
+                return Err(format!("UNSUPPORTED_RECORD: {}", dns.record));
+            }
+        }
+        let downloads = [self.throughput.as_ref(), self.tunnel.as_ref().and_then(|t| t.download.as_ref())];
+        for throughput in downloads.into_iter().flatten() {
+            if !(1..=MAX_THROUGHPUT_BYTES).contains(&throughput.max_bytes) || !(1..=60_000).contains(&throughput.max_ms) {
+                return Err("THROUGHPUT_LIMITS_OUT_OF_RANGE".to_string());
+            }
+        }
+        if let Some(tunnel) = &self.tunnel {
+            if !(1..=MAX_COUNT).contains(&tunnel.count) {
+                return Err(format!("COUNT_OUT_OF_RANGE: {}", tunnel.count));
+            }
+            parse_target(&tunnel.target, 80)?;
+            if let Some(auth) = &tunnel.auth {
+                if tunnel.proxy.is_none() {
+                    return Err("TUNNEL_AUTH_NEEDS_PROXY".to_string());
+                }
+                // Each field goes out with a one-byte length
+                if !(1..=255).contains(&auth.username.len()) || auth.password.len() > 255 {
+                    return Err("INVALID_PROXY_AUTH".to_string());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Host and port of `raw`: a name or address, `host:port`, `[v6]:port`, a bare IPv6 address
+/// or a URL. The port is the URL scheme's, or `default_port`, when not given.
+pub fn parse_target(raw: &str, default_port: u16) -> Result<(String, u16), String> {
+    let raw = raw.trim();
+    if raw.is_empty() {
+        return Err("EMPTY_TARGET".to_string());
+    }
+    let invalid = || format!("INVALID_TARGET: {}", raw);
+    if raw.contains("://") {
+        let url = url::Url::parse(raw).map_err(|_| invalid())?;
+        let host = url.host_str().filter(|h| !h.is_empty()).ok_or_else(invalid)?;
+        let port = url.port_or_known_default().unwrap_or(default_port);
+        return Ok((host.trim_start_matches('[').trim_end_matches(']').to_string(), port));
+    }
+    // Plain addresses first: a bare IPv6 address is full of colons that are not a port
+    if let Ok(ip) = raw.parse::<IpAddr>() {
+        return Ok((ip.to_string(), default_port));
+    }
+    if let Ok(addr) = raw.parse::<SocketAddr>() {
+        return Ok((addr.ip().to_string(), addr.port()));
+    }
+    if let Some(v6) = raw.strip_prefix('[').and_then(|r| r.strip_suffix(']')) {
+        return v6.parse::<Ipv6Addr>().map(|ip| (ip.to_string(), default_port)).map_err(|_| invalid());
+    }
+    let (host, port) = match raw.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse::<u16>().ok().filter(|p| *p != 0).ok_or_else(invalid)?),
+        None => (raw, default_port),
+    };
+    let name = host.strip_suffix('.').unwrap_or(host);
+    let label_ok = |l: &str| (1..=63).contains(&l.len()) && l.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+    if name.len() > 253 || !name.split('.').all(label_ok) {
+        return Err(invalid());
+    }
+    Ok((name.to_string(), port))
+}
+
+/// Connect round trips; times in milliseconds over the attempts that connected.
+#[derive(Serialize, Debug, Clone, PartialEq, Default)]
+pub struct ConnectReport {
+    pub address: String,
+    pub sent: u32,
+    pub received: u32,
+    pub loss_pct: f64,
+    pub min_ms: Option<f64>,
+    pub avg_ms: Option<f64>,
+    pub max_ms: Option<f64>,
+    pub p50_ms: Option<f64>,
+    pub p90_ms: Option<f64>,
+    pub p99_ms: Option<f64>,
+    // RFC 3550 interarrival jitter over consecutive round trips
+    pub jitter_ms: Option<f64>,
+    // Looking the target up, before the first attempt
+    pub resolve_ms: Option<f64>,
+    // One code per failed attempt
+    pub errors: Vec<String>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct DnsReport {
+    pub name: String,
+    // Resolver asked, or `system`
+    pub server: String,
+    pub ms: Option<f64>,
+    pub addresses: Vec<IpAddr>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct TlsReport {
+    pub server_name: String,
+    pub connect_ms: Option<f64>,
+    // ClientHello out to ServerHello back
+    pub hello_ms: Option<f64>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ThroughputReport {
+    pub endpoint: String,
+    pub bytes: u64,
+    pub first_byte_ms: Option<f64>,
+    // From the first byte to the last
+    pub transfer_ms: f64,
+    pub bytes_per_sec: u64,
+    pub error: Option<String>,
+}
+
+/// Performance through the upstream, as an app behind the VPN would see it.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct TunnelReport {
+    pub proxy: String,
+    // Session upstream the probe went through; `null` for an explicitly given proxy
+    pub upstream: Option<String>,
+    // The one server the probe measured; `null` for a pool, where ss-local picks a server
+    // per connection, and for an explicit proxy. Only these results are ranked
+    pub server: Option<String>,
+    pub target: String,
+    // Connecting to the proxy until its CONNECT reply: reaching the server and back
+    pub handshake: ConnectReport,
+    // Request sent until the first response byte: the whole path to the target and back.
+    // Attempts whose handshake failed count as lost here too
+    pub first_byte: ConnectReport,
+    pub download: Option<ThroughputReport>,
+    // Why nothing was measured at all
+    pub error: Option<String>,
+}
+
+/// The `runDiagnostics` document. Probes that were not asked for are `null`.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct DiagnosticsReport {
+    pub version: u32,
+    pub cancelled: bool,
+    pub connect: Option<ConnectReport>,
+    pub dns: Option<DnsReport>,
+    pub tls: Option<TlsReport>,
+    pub throughput: Option<ThroughputReport>,
+    pub tunnel: Option<TunnelReport>,
+}
+
+/// Token for the next run; `cancel` ends every run holding one from before.
+pub fn cancel_token() -> CancellationToken {
+    DIAGNOSTICS_STOP.lock().map(|t| t.child_token()).unwrap_or_default()
+}
+
+pub fn cancel() {
+    if let Ok(mut current) = DIAGNOSTICS_STOP.lock() {
+        current.cancel();
+        *current = CancellationToken::new();
+    }
+}
+
+/// Runs the probes in `config`, one after the other.
+pub async fn run(config: &DiagnosticsConfig, stop: &CancellationToken) -> DiagnosticsReport {
+    let timeout = Duration::from_millis(config.timeout_ms);
+    let mut report = DiagnosticsReport {
+        version: DIAGNOSTICS_VERSION,
+        cancelled: false,
+        connect: None,
+        dns: None,
+        tls: None,
+        throughput: None,
+        tunnel: None,
+    };
+
+    if let Some(dns) = &config.dns {
+        report.dns = Some(dns_timing(dns, timeout, stop).await);
+    }
+    if !config.target.is_empty() && !stop.is_cancelled() {
+        let interval = Duration::from_millis(config.interval_ms);
+        report.connect = Some(connect_rtt(&config.target, config.count, timeout, interval, stop).await);
+    }
+    if let (Some(tls), false) = (&config.tls, stop.is_cancelled()) {
+        report.tls = Some(tls_timing(&config.target, tls, timeout, stop).await);
+    }
+    if let (Some(probe), false) = (&config.throughput, stop.is_cancelled()) {
+        report.throughput = Some(throughput(probe, timeout, stop).await);
+    }
+    if let (Some(probe), false) = (&config.tunnel, stop.is_cancelled()) {
+        let tunnel = tunnel(probe, timeout, stop).await;
+        if !stop.is_cancelled() {
+            remember(&tunnel);
+        }
+        report.tunnel = Some(tunnel);
+    }
+    report.cancelled = stop.is_cancelled();
+    report
+}
+
+fn millis(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.0
+}
+
+// Bounded by `timeout` and by `stop`; errors come back as codes
+async fn step<T, E: std::fmt::Display>(
+    what: &str,
+    timeout: Duration,
+    stop: &CancellationToken,
+    work: impl Future<Output = Result<T, E>>,
+) -> Result<T, String> {
+    tokio::select! {
+        _ = stop.cancelled() => Err("CANCELLED".to_string()),
+        result = tokio::time::timeout(timeout, work) => match result {
+            Ok(Ok(value)) => Ok(value),
+            Ok(Err(e)) => Err(format!("{}_FAILED: {}", what, e)),
+            Err(_) => Err(format!("{}_TIMEOUT", what)),
+        },
+    }
+}
+
+// The first address `target` resolves to, and how long that took
+async fn resolve(target: &str, default_port: u16, timeout: Duration, stop: &CancellationToken) -> Result<(SocketAddr, f64), String> {
+    let (host, port) = parse_target(target, default_port)?;
+    let start = Instant::now();
+    let addrs = step("RESOLVE", timeout, stop, tokio::net::lookup_host((host.as_str(), port))).await?;
+    let addr = addrs.into_iter().next().ok_or_else(|| format!("RESOLVE_FAILED: {}", host))?;
+    Ok((addr, millis(start.elapsed())))
+}
+
+/// `count` TCP connects to `target`, `interval` apart; port 80 unless the target names one.
+pub async fn connect_rtt(target: &str, count: u32, timeout: Duration, interval: Duration, stop: &CancellationToken) -> ConnectReport {
+    let (addr, resolve_ms) = match resolve(target, 80, timeout, stop).await {
+        Ok(resolved) => resolved,
+        Err(e) => return summarize(target.to_string(), 0, &[], vec![e]),
+    };
+    let mut rtts = Vec::with_capacity(count as usize);
+    let mut errors = Vec::new();
+    let mut sent = 0;
+    for i in 0..count {
+        if i > 0 && !interval.is_zero() {
+            tokio::select! {
+                _ = stop.cancelled() => break,
+                _ = tokio::time::sleep(interval) => {}
+            }
+        }
+        if stop.is_cancelled() {
+            break;
+        }
+        sent += 1;
+        let start = Instant::now();
+        match step("CONNECT", timeout, stop, TcpStream::connect(addr)).await {
+            Ok(_) => rtts.push(millis(start.elapsed())),
+            Err(e) => errors.push(e),
+        }
+    }
+    ConnectReport { resolve_ms: Some(resolve_ms), ..summarize(addr.to_string(), sent, &rtts, errors) }
+}
+
+/// Loss, nearest-rank percentiles and RFC 3550 jitter over `rtts`, in the order measured.
+pub fn summarize(address: String, sent: u32, rtts: &[f64], errors: Vec<String>) -> ConnectReport {
+    let received = rtts.len() as u32;
+    let loss_pct = if sent == 0 { 100.0 } else { 100.0 * (sent - received) as f64 / sent as f64 };
+    let mut report = ConnectReport { address, sent, received, loss_pct, errors, ..ConnectReport::default() };
+    if rtts.is_empty() {
+        return report;
+    }
+    let mut sorted = rtts.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let percentile = |p: f64| sorted[((p / 100.0 * sorted.len() as f64).ceil() as usize).clamp(1, sorted.len()) - 1];
+    // J += (|D| - J) / 16, D being the change between consecutive round trips
+    let jitter = rtts.windows(2).fold(0.0, |j, pair| j + ((pair[1] - pair[0]).abs() - j) / 16.0);
+
+    report.min_ms = Some(sorted[0]);
+    report.max_ms = Some(sorted[sorted.len() - 1]);
+    report.avg_ms = Some(rtts.iter().sum::<f64>() / rtts.len() as f64);
+    report.p50_ms = Some(percentile(50.0));
+    report.p90_ms = Some(percentile(90.0));
+    report.p99_ms = Some(percentile(99.0));
+    report.jitter_ms = Some(jitter);
+    report
+}
+
+/// Times one lookup of `probe.name`, through the system resolver or a given server.
+pub async fn dns_timing(probe: &DnsProbe, timeout: Duration, stop: &CancellationToken) -> DnsReport {
+    let start = Instant::now();
+    let result = match &probe.server {
+        None => step("DNS", timeout, stop, tokio::net::lookup_host((probe.name.as_str(), 0)))
+            .await
+            .map(|addrs| addrs.map(|a| a.ip()).collect::<Vec<_>>()),
+        Some(server) => match server.parse::<SocketAddr>() {
+            Ok(server) => step("DNS", timeout, stop, query_server(server, &probe.name, &probe.record)).await.and_then(|r| r),
+            Err(_) => Err(format!("INVALID_DNS_SERVER: {}", server)),
+        },
+    };
+    let ms = millis(start.elapsed());
+    let server = probe.server.clone().unwrap_or_else(|| "system".to_string());
+    match result {
+        Ok(addresses) => DnsReport { name: probe.name.clone(), server, ms: Some(ms), addresses, error: None },
+        Err(e) => DnsReport { name: probe.name.clone(), server, ms: None, addresses: Vec::new(), error: Some(e) },
+    }
+}
+
+async fn query_server(server: SocketAddr, name: &str, record: &str) -> std::io::Result<Result<Vec<IpAddr>, String>> {
+    let bind: SocketAddr = if server.is_ipv4() { ([0, 0, 0, 0], 0).into() } else { (std::net::Ipv6Addr::UNSPECIFIED, 0).into() };
+    let socket = UdpSocket::bind(bind).await?;
+    let id: u16 = rand::random();
+    let qtype = if record == "AAAA" { 28 } else { 1 };
+    socket.send_to(&dns_query(id, name, qtype), server).await?;
+    let mut buf = [0u8; 1500];
+    loop {
+        let (n, from) = socket.recv_from(&mut buf).await?;
+        // Stray datagrams and answers to someone else do not end the wait
+        if from == server && buf[..n].starts_with(&id.to_be_bytes()) {
+            return Ok(parse_answers(&buf[..n]));
+        }
+    }
+}
+
+fn dns_query(id: u16, name: &str, qtype: u16) -> Vec<u8> {
+    let mut query = Vec::with_capacity(name.len() + 18);
+    query.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired, one question
+    query.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+    for label in name.trim_end_matches('.').split('.') {
+        query.push(label.len().min(63) as u8);
+        query.extend_from_slice(&label.as_bytes()[..label.len().min(63)]);
+    }
+    query.push(0);
+    query.extend_from_slice(&qtype.to_be_bytes());
+    query.extend_from_slice(&[0x00, 0x01]);
+    query
+}
+
+fn skip_name(msg: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *msg.get(pos)? as usize;
+        match len {
+            0 => return Some(pos + 1),
+            // Compression pointer ends the name
+            l if l & 0xC0 == 0xC0 => return Some(pos + 2),
+            l => pos += 1 + l,
+        }
+    }
+}
+
+fn parse_answers(msg: &[u8]) -> Result<Vec<IpAddr>, String> {
+    let field = |at: usize| msg.get(at..at + 2).map(|b| u16::from_be_bytes([b[0], b[1]]));
+    let flags = field(2).ok_or("DNS_SHORT_REPLY")?;
+    if flags & 0x000F != 0 {
+        return Err(format!("DNS_RCODE_{}", flags & 0x000F));
+    }
+    let (questions, answers) = (field(4).ok_or("DNS_SHORT_REPLY")?, field(6).ok_or("DNS_SHORT_REPLY")?);
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(msg, pos).ok_or("DNS_BAD_REPLY")? + 4;
+    }
+    let mut out = Vec::new();
+    for _ in 0..answers {
+        pos = skip_name(msg, pos).ok_or("DNS_BAD_REPLY")?;
+        let (rtype, rdlen) = (field(pos).ok_or("DNS_BAD_REPLY")?, field(pos + 8).ok_or("DNS_BAD_REPLY")? as usize);
+        let data = msg.get(pos + 10..pos + 10 + rdlen).ok_or("DNS_BAD_REPLY")?;
+        match (rtype, data.len()) {
+            (1, 4) => out.push(IpAddr::from(<[u8; 4]>::try_from(data).unwrap_or_default())),
+            (28, 16) => out.push(IpAddr::from(<[u8; 16]>::try_from(data).unwrap_or_default())),
+            // CNAMEs and the like carry no address
+            _ => {}
+        }
+        pos += 10 + rdlen;
+    }
+    Ok(out)
+}
+
+/// Times the TCP connect and the ClientHello/ServerHello exchange with the target.
+pub async fn tls_timing(target: &str, probe: &TlsProbe, timeout: Duration, stop: &CancellationToken) -> TlsReport {
+    let host = parse_target(target, 443).map(|(host, _)| host).unwrap_or_default();
+    let server_name = probe.server_name.clone().unwrap_or(host);
+    let mut report = TlsReport { server_name: server_name.clone(), connect_ms: None, hello_ms: None, error: None };
+
+    let addr = match resolve(target, 443, timeout, stop).await {
+        Ok((addr, _)) => addr,
+        Err(e) => {
+            report.error = Some(e);
+            return report;
+        }
+    };
+    let start = Instant::now();
+    let mut stream = match step("CONNECT", timeout, stop, TcpStream::connect(addr)).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            report.error = Some(e);
+            return report;
+        }
+    };
+    report.connect_ms = Some(millis(start.elapsed()));
+
+    let start = Instant::now();
+    let hello = crate::obfs::tls_client_hello(&server_name, &[]);
+    let exchange = async {
+        stream.write_all(&hello).await?;
+        crate::obfs::read_tls_record(&mut stream).await
+    };
+    match step("TLS", timeout, stop, exchange).await {
+        // Handshake record opening with ServerHello
+        Ok((0x16, body)) if body.first() == Some(&0x02) => report.hello_ms = Some(millis(start.elapsed())),
+        Ok((0x15, body)) => report.error = Some(format!("TLS_ALERT: {}", body.get(1).copied().unwrap_or(0))),
+        Ok((kind, _)) => report.error = Some(format!("TLS_UNEXPECTED_RECORD: {}", kind)),
+        Err(e) => report.error = Some(e),
+    }
+    report
+}
+
+/// Reads from `probe.endpoint` until it closes or a limit is hit.
+pub async fn throughput(probe: &ThroughputProbe, timeout: Duration, stop: &CancellationToken) -> ThroughputReport {
+    let mut report = ThroughputReport {
+        endpoint: probe.endpoint.clone(),
+        bytes: 0,
+        first_byte_ms: None,
+        transfer_ms: 0.0,
+        bytes_per_sec: 0,
+        error: None,
+    };
+    let addr = match resolve(&probe.endpoint, 80, timeout, stop).await {
+        Ok((addr, _)) => addr,
+        Err(e) => {
+            report.error = Some(e);
+            return report;
+        }
+    };
+    let start = Instant::now();
+    match step("CONNECT", timeout, stop, TcpStream::connect(addr)).await {
+        Ok(stream) => download(stream, start, probe, timeout, stop, &mut report).await,
+        Err(e) => report.error = Some(e),
+    }
+    report
+}
+
+// Sends the probe's request on `stream` and times what comes back; `start` is when connecting began
+async fn download(
+    mut stream: TcpStream,
+    start: Instant,
+    probe: &ThroughputProbe,
+    timeout: Duration,
+    stop: &CancellationToken,
+    report: &mut ThroughputReport,
+) {
+    if let Some(request) = &probe.request {
+        if let Err(e) = step("SEND", timeout, stop, stream.write_all(request.as_bytes())).await {
+            report.error = Some(e);
+            return;
+        }
+    }
+
+    let deadline = tokio::time::Instant::now() + Duration::from_millis(probe.max_ms);
+    let mut first_byte: Option<Instant> = None;
+    let mut buf = vec![0u8; READ_CHUNK];
+    while report.bytes < probe.max_bytes {
+        let read = tokio::select! {
+            _ = stop.cancelled() => break,
+            _ = tokio::time::sleep_until(deadline) => break,
+            read = tokio::time::timeout(timeout, stream.read(&mut buf)) => read,
+        };
+        match read {
+            Ok(Ok(0)) => break,
+            Ok(Ok(n)) => {
+                let now = Instant::now();
+                if first_byte.is_none() {
+                    first_byte = Some(now);
+                    report.first_byte_ms = Some(millis(now.duration_since(start)));
+                }
+                report.bytes += n as u64;
+            }
+            Ok(Err(e)) => {
+                report.error = Some(format!("READ_FAILED: {}", e));
+                break;
+            }
+            Err(_) => {
+                report.error = Some("READ_TIMEOUT".to_string());
+                break;
+            }
+        }
+    }
+    if let Some(first) = first_byte {
+        let elapsed = first.elapsed();
+        report.transfer_ms = millis(elapsed);
+        if !elapsed.is_zero() {
+            report.bytes_per_sec = (report.bytes as f64 / elapsed.as_secs_f64()) as u64;
+        }
+    }
+}
+
+// --- TUNNEL PROBE ---
+// The app is excluded from its own VPN, so to see the tunnel it talks to ss-local directly:
+// SOCKS5 CONNECT through it times the way to the server, a request through the connection
+// times the full path. Results are kept per server address, so the server picker can rank
+// every node the user has probed, not just the current one.
+
+// The running session's ss-local, the upstream label it serves and its single server
+fn session_proxy() -> Result<(SocketAddr, String, Option<String>), String> {
+    if crate::engine::Status::current() != crate::engine::Status::Running {
+        return Err("TUNNEL_NOT_RUNNING".to_string());
+    }
+    match crate::health::upstream() {
+        // Only a shadowsocks upstream sits behind the local proxy port
+        Some(label) if label.starts_with("ss://") => {
+            Ok((([127, 0, 0, 1], PROXY_PORT.load(Ordering::Relaxed)).into(), label, crate::health::server()))
+        }
+        _ => Err("TUNNEL_NOT_SHADOWSOCKS".to_string()),
+    }
+}
+
+// A connection to `host:port` through `proxy`, with the time the CONNECT took
+async fn open_tunnel(
+    proxy: SocketAddr,
+    auth: Option<&ProxyAuth>,
+    host: &str,
+    port: u16,
+    timeout: Duration,
+    stop: &CancellationToken,
+) -> Result<(TcpStream, Instant, f64), String> {
+    let start = Instant::now();
+    let mut stream = step("PROXY_CONNECT", timeout, stop, TcpStream::connect(proxy)).await?;
+    let connect = async {
+        socks5_handshake(&mut stream, auth).await?;
+        socks5_connect(&mut stream, host, port).await
+    };
+    step("SOCKS5", timeout, stop, connect).await?;
+    Ok((stream, start, millis(start.elapsed())))
+}
+
+/// Round trips and an optional download through a SOCKS5 proxy, the session's by default.
+pub async fn tunnel(probe: &TunnelProbe, timeout: Duration, stop: &CancellationToken) -> TunnelReport {
+    let empty = |address: &str| summarize(address.to_string(), 0, &[], Vec::new());
+    let mut report = TunnelReport {
+        proxy: String::new(),
+        upstream: None,
+        server: None,
+        target: probe.target.clone(),
+        handshake: empty(""),
+        first_byte: empty(&probe.target),
+        download: None,
+        error: None,
+    };
+    let proxy = match &probe.proxy {
+        Some(proxy) => proxy.parse::<SocketAddr>().map_err(|_| format!("INVALID_PROXY: {}", proxy)),
+        None => session_proxy().map(|(proxy, label, server)| {
+            report.upstream = Some(label);
+            report.server = server;
+            proxy
+        }),
+    };
+    let (proxy, (host, port)) = match proxy.and_then(|proxy| Ok((proxy, parse_target(&probe.target, 80)?))) {
+        Ok(ok) => ok,
+        Err(e) => {
+            report.error = Some(e);
+            return report;
+        }
+    };
+    report.proxy = proxy.to_string();
+    let request = probe
+        .request
+        .clone()
+        .unwrap_or_else(|| format!("GET /generate_204 HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", host));
+
+    let (mut handshakes, mut first_bytes) = (Vec::new(), Vec::new());
+    let (mut handshake_errors, mut first_byte_errors) = (Vec::new(), Vec::new());
+    let mut sent = 0;
+    for _ in 0..probe.count {
+        if stop.is_cancelled() {
+            break;
+        }
+        sent += 1;
+        let mut stream = match open_tunnel(proxy, probe.auth.as_ref(), &host, port, timeout, stop).await {
+            Ok((stream, _, ms)) => {
+                handshakes.push(ms);
+                stream
+            }
+            Err(e) => {
+                handshake_errors.push(e.clone());
+                first_byte_errors.push(e);
+                continue;
+            }
+        };
+        let start = Instant::now();
+        let exchange = async {
+            stream.write_all(request.as_bytes()).await?;
+            let mut byte = [0u8; 1];
+            match stream.read(&mut byte).await? {
+                0 => Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "CLOSED_BEFORE_REPLY")),
+                _ => Ok(()),
+            }
+        };
+        match step("FIRST_BYTE", timeout, stop, exchange).await {
+            Ok(()) => first_bytes.push(millis(start.elapsed())),
+            Err(e) => first_byte_errors.push(e),
+        }
+    }
+    report.handshake = summarize(report.proxy.clone(), sent, &handshakes, handshake_errors);
+    report.first_byte = summarize(probe.target.clone(), sent, &first_bytes, first_byte_errors);
+
+    if let (Some(download_probe), false) = (&probe.download, stop.is_cancelled()) {
+        let mut download_report = ThroughputReport {
+            endpoint: download_probe.endpoint.clone(),
+            bytes: 0,
+            first_byte_ms: None,
+            transfer_ms: 0.0,
+            bytes_per_sec: 0,
+            error: None,
+        };
+        let opened = match parse_target(&download_probe.endpoint, 80) {
+            Ok((host, port)) => open_tunnel(proxy, probe.auth.as_ref(), &host, port, timeout, stop).await,
+            Err(e) => Err(e),
+        };
+        match opened {
+            Ok((stream, start, _)) => download(stream, start, download_probe, timeout, stop, &mut download_report).await,
+            Err(e) => download_report.error = Some(e),
+        }
+        report.download = Some(download_report);
+    }
+    report
+}
+
+/// Best node first: those that answered, then by end-to-end loss, median time to first
+/// byte and download speed.
+pub fn rank(reports: &mut [TunnelReport]) {
+    let key = |r: &TunnelReport| {
+        let speed = r.download.as_ref().map_or(0, |d| d.bytes_per_sec);
+        (r.first_byte.received == 0, r.first_byte.loss_pct, r.first_byte.p50_ms.unwrap_or(f64::MAX), std::cmp::Reverse(speed))
+    };
+    reports.sort_by(|a, b| {
+        let (a, b) = (key(a), key(b));
+        a.0.cmp(&b.0).then(a.1.total_cmp(&b.1)).then(a.2.total_cmp(&b.2)).then(a.3.cmp(&b.3))
+    });
+}
+
+/// Keeps the latest result of each server; pools and explicit proxies are not ranked.
+pub(crate) fn remember(report: &TunnelReport) {
+    let Some(server) = report.server.clone() else { return };
+    if report.error.is_none() {
+        if let Ok(mut results) = TUNNEL_RESULTS.lock() {
+            results.insert(server, report.clone());
+        }
+    }
+}
+
+/// Latest tunnel result of every server probed in this process, best first.
+pub fn ranking() -> Vec<TunnelReport> {
+    let mut reports: Vec<TunnelReport> = TUNNEL_RESULTS.lock().map(|r| r.values().cloned().collect()).unwrap_or_default();
+    rank(&mut reports);
+    reports
+}
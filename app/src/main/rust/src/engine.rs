@@ -0,0 +1,209 @@
+use serde::Serialize;
+use std::fs::File;
+use std::io::BufWriter;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, OnceLock};
+use tun2proxy::CancellationToken;
+use crate::capture::{self, CaptureConfig, CaptureSummary};
+use crate::common::*;
+use crate::config::{ApplyReport, ConfigError, EngineConfig};
+use crate::diagnostics::{self, DiagnosticsConfig, DiagnosticsReport, TunnelReport};
+use crate::energy::EnergyReport;
+use crate::health::HealthSnapshot;
+
+// --- ENGINE: PLAIN RUST API ---
+// What the JNI functions and the Linux CLI both drive. Engine state lives in process-wide
+// globals, so an `Engine` is a handle onto them: one session runs at a time, and `stop` from
+// any handle ends it.
+
+static LOG_SINK: OnceLock<fn(&str)> = OnceLock::new();
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Status {
+    Stopped,
+    Starting,
+    Running,
+    Error,
+    Unknown,
+}
+
+impl Status {
+    pub fn current() -> Self {
+        match CORE_STATUS.load(Ordering::SeqCst) {
+            0 => Status::Stopped,
+            1 => Status::Starting,
+            2 => Status::Running,
+            3 => Status::Error,
+            _ => Status::Unknown,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct KillSwitchStats {
+    pub enabled: bool,
+    // The upstream is down and packets are being dropped right now
+    pub engaged: bool,
+    pub blocked_packets: u64,
+    pub blocked_bytes: u64,
+    pub allowed_packets: u64,
+    pub trips: u64,
+}
+
+/// Counters since the process started; the top level of the `getCoreHealth` document.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct EngineStats {
+    pub status: Status,
+    pub tcp: u64,
+    pub udp: u64,
+    pub other: u64,
+    pub bytes: u64,
+    pub port: u16,
+    pub udp_mode: &'static str,
+    pub rejects_sent: u64,
+    pub malformed_frames: u64,
+    pub inbound_dropped: u64,
+    pub inbound_unsolicited: u64,
+    pub kill_switch: KillSwitchStats,
+}
+
+pub struct Engine {
+    _handle: (),
+}
+
+impl Engine {
+    /// Validates and applies `config`, replacing whatever was applied before.
+    pub fn new(config: EngineConfig) -> Result<Self, Vec<ConfigError>> {
+        crate::config::apply(config)?;
+        Ok(Engine { _handle: () })
+    }
+
+    /// The engine as configured so far, for callers that set it up one field at a time.
+    pub fn current() -> Self {
+        Engine { _handle: () }
+    }
+
+    pub fn config(&self) -> Arc<EngineConfig> {
+        crate::config::snapshot()
+    }
+
+    pub fn apply(&self, config: EngineConfig) -> Result<ApplyReport, Vec<ConfigError>> {
+        crate::config::apply(config)
+    }
+
+    /// Runs a VPN session on `fd` (a TUN carrying raw IP packets) until the fd closes or
+    /// `stop` is called. Falls back to the passive shield when there is no upstream.
+    pub fn run(&self, fd: RawFd) -> Status {
+        crate::vpn::start_vpn_loop(fd, arm_stop());
+        Status::current()
+    }
+
+    /// Runs only the passive shield on `fd`: classify and count, never forward.
+    pub fn run_shield(&self, fd: RawFd) -> Status {
+        crate::vpn::run_passive_shield(fd, arm_stop());
+        Status::current()
+    }
+
+    /// Ends the session in progress, if any. The `run` call returns once it has wound down.
+    pub fn stop(&self) {
+        if let Some(stop) = SESSION_STOP.lock().ok().and_then(|mut s| s.take()) {
+            stop.cancel();
+        }
+    }
+
+    pub fn stats(&self) -> EngineStats {
+        EngineStats {
+            status: Status::current(),
+            tcp: TCP_COUNT.load(Ordering::Relaxed),
+            udp: UDP_COUNT.load(Ordering::Relaxed),
+            other: OTHER_COUNT.load(Ordering::Relaxed),
+            bytes: BYTES_PROCESSED.load(Ordering::Relaxed),
+            port: PROXY_PORT.load(Ordering::Relaxed),
+            udp_mode: crate::udp_relay::UdpMode::from_u8(UDP_MODE.load(Ordering::Relaxed)).as_str(),
+            rejects_sent: REJECTS_SENT.load(Ordering::Relaxed),
+            malformed_frames: MALFORMED_FRAMES.load(Ordering::Relaxed),
+            inbound_dropped: INBOUND_DROPPED.load(Ordering::Relaxed),
+            inbound_unsolicited: INBOUND_UNSOLICITED.load(Ordering::Relaxed),
+            kill_switch: KillSwitchStats {
+                enabled: KILL_SWITCH.load(Ordering::Relaxed),
+                engaged: crate::killswitch::engaged(),
+                blocked_packets: LEAK_BLOCKED_PACKETS.load(Ordering::Relaxed),
+                blocked_bytes: LEAK_BLOCKED_BYTES.load(Ordering::Relaxed),
+                allowed_packets: LEAK_ALLOWED_PACKETS.load(Ordering::Relaxed),
+                trips: LEAK_TRIPS.load(Ordering::Relaxed),
+            },
+        }
+    }
+
+    /// Starts recording packets into memory, replacing a recording already in progress.
+    pub fn start_capture(&self, config: CaptureConfig) {
+        capture::start(config);
+    }
+
+    /// Ends the recording and writes it to `path` as pcapng; `None` just discards it.
+    pub fn stop_capture(&self, path: Option<&Path>) -> Result<CaptureSummary, String> {
+        let recording = capture::stop().ok_or("NO_CAPTURE")?;
+        if let Some(path) = path {
+            let file = File::create(path).map_err(|e| format!("CAPTURE_WRITE_FAILED: {}", e))?;
+            recording
+                .write_pcapng(&mut BufWriter::new(file))
+                .map_err(|e| format!("CAPTURE_WRITE_FAILED: {}", e))?;
+        }
+        Ok(recording.summary())
+    }
+
+    /// Counters plus session state; serialized as the `getCoreHealth` document.
+    pub fn health(&self) -> HealthSnapshot {
+        crate::health::snapshot(self.stats())
+    }
+
+    /// Battery the blocking policy saved since the process started, with the model used.
+    pub fn energy_savings(&self) -> EnergyReport {
+        crate::energy::report()
+    }
+
+    /// Runs the probes in `config` and waits for them; `cancel_diagnostics` cuts them short.
+    /// Blocks the calling thread, so it must not be called from inside a runtime.
+    pub fn diagnose(&self, config: &DiagnosticsConfig) -> Result<DiagnosticsReport, String> {
+        config.validate()?;
+        let stop = diagnostics::cancel_token();
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|e| format!("RUNTIME_FAILED: {}", e))?;
+        Ok(rt.block_on(diagnostics::run(config, &stop)))
+    }
+
+    /// Latest tunnel probe of each upstream probed so far, best first.
+    pub fn tunnel_ranking(&self) -> Vec<TunnelReport> {
+        diagnostics::ranking()
+    }
+
+    /// Ends every diagnostics run in progress; each returns what it measured so far.
+    pub fn cancel_diagnostics(&self) {
+        diagnostics::cancel();
+    }
+
+    /// Where engine logs go when there is no JVM to send them to. Set once per process.
+    pub fn set_log_sink(sink: fn(&str)) {
+        let _ = LOG_SINK.set(sink);
+    }
+}
+
+// A fresh token per session, so a `stop` that raced the previous session's end is not kept
+fn arm_stop() -> CancellationToken {
+    let stop = CancellationToken::new();
+    if let Ok(mut current) = SESSION_STOP.lock() {
+        *current = Some(stop.clone());
+    }
+    stop
+}
+
+pub(crate) fn forward_log(msg: &str) {
+    if let Some(sink) = LOG_SINK.get() {
+        sink(msg);
+    }
+}
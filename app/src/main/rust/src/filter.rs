@@ -0,0 +1,258 @@
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::atomic::Ordering;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::mpsc;
+use crate::capture::{self, Direction};
+use crate::common::*;
+use crate::energy;
+use crate::killswitch::{self, Verdict};
+use crate::packet::{ip_len, PacketInfo, Transport, TCP_ACK, TCP_SYN};
+use crate::reject::reject_for;
+use crate::routing::{Action, BlockMode, FlowTable, Unsolicited};
+
+// --- TRUE LOCKDOWN: FILTERED TUN WRAPPER ---
+// Sits between the TUN and tun2proxy. Every inner read is one IP frame; it is read straight
+// into the caller's buffer and only handed over (by advancing the buffer) when it should
+// reach the proxy. Dropped and Direct frames never leave this loop. Writes are the proxy's
+// replies: they inherit the verdict of the flow they answer before reaching the apps.
+
+// Frames consumed per wakeup before yielding, so a drop storm cannot starve the runtime
+const FRAME_BUDGET: usize = 64;
+
+const DNS_PORT: u16 = 53;
+
+pub type UidResolver = fn(u16, bool) -> Option<u32>;
+
+enum Outcome {
+    // Hand the first `len` bytes to tun2proxy
+    Deliver(usize),
+    // Dropped, rejected or sent direct
+    Consumed,
+}
+
+pub struct FilteredTun<T> {
+    inner: T,
+    flows: FlowTable,
+    // Packets of flows routed Direct go to the proxy-less stack
+    direct: Option<mpsc::Sender<Vec<u8>>>,
+    resolve_uid: UidResolver,
+}
+
+impl<T> FilteredTun<T> {
+    pub fn new(inner: T, direct: Option<mpsc::Sender<Vec<u8>>>, resolve_uid: UidResolver) -> Self {
+        FilteredTun { inner, flows: FlowTable::default(), direct, resolve_uid }
+    }
+
+    /// Destinations in `pool` are virtual DNS answers and stay on the proxy stack.
+    pub fn with_virtual_dns_pool(mut self, pool: (IpAddr, u8)) -> Self {
+        self.flows.set_virtual_dns_pool(pool);
+        self
+    }
+
+    /// Destinations in the interface's own `subnets` are not LAN and skip the bypass.
+    pub fn with_tun_subnets(mut self, subnets: &[(IpAddr, u8)]) -> Self {
+        self.flows.set_tun_subnets(subnets);
+        self
+    }
+
+    #[cfg(test)]
+    pub fn inner_ref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T: AsyncWrite + Unpin> FilteredTun<T> {
+    fn route(&mut self, frame: &[u8], cx: &mut Context<'_>) -> Outcome {
+        // Kernel reads never merge packets, but a short buffer truncates them: a frame
+        // shorter than its own header says is broken, a longer one carries padding
+        let len = match ip_len(frame) {
+            Some(len) if len > 0 && len <= frame.len() => len,
+            _ => {
+                MALFORMED_FRAMES.fetch_add(1, Ordering::Relaxed);
+                capture::record(Direction::Outbound, None, "MALFORMED", frame);
+                return Outcome::Consumed;
+            }
+        };
+        let frame = &frame[..len];
+        let info = PacketInfo::parse(frame);
+
+        let held = killswitch::verdict(info.as_ref().map(|i| i.dst.ip()), len);
+        let action = match held {
+            Verdict::Pass => self.flows.classify_frame(frame, info.as_ref(), self.resolve_uid),
+            Verdict::Allow => Action::Direct,
+            Verdict::Drop => Action::Block,
+        };
+        account_outbound(info.as_ref(), action, len);
+        match (held, action) {
+            // Held for a tunnel that is down, not kept off the network by policy
+            (Verdict::Drop, _) => {}
+            (_, Action::Block) => energy::blocked(len),
+            _ => energy::passed(),
+        }
+        if capture::active() {
+            let uid = info.as_ref().and_then(|info| self.flows.uid_of(info));
+            let verdict = match action {
+                Action::Proxy => "PROXY",
+                Action::Direct => "DIRECT",
+                Action::Block => "BLOCK",
+            };
+            capture::record(Direction::Outbound, uid, verdict, frame);
+        }
+        match action {
+            Action::Proxy => Outcome::Deliver(len),
+            Action::Direct => match &self.direct {
+                Some(direct) => {
+                    // A full queue means the direct stack is behind; TCP will resend
+                    let _ = direct.try_send(frame.to_vec());
+                    Outcome::Consumed
+                }
+                None => Outcome::Deliver(len),
+            },
+            Action::Block => {
+                if self.flows.block_mode() == BlockMode::Reject {
+                    if let Some(reply) = info.as_ref().and_then(|info| reject_for(info, frame)) {
+                        // Best effort: TUN writes do not block, and a lost reject only costs a retry
+                        if let Poll::Ready(Ok(_)) = Pin::new(&mut self.inner).poll_write(cx, &reply) {
+                            REJECTS_SENT.fetch_add(1, Ordering::Relaxed);
+                            let connect = matches!(info.as_ref().map(|i| i.transport), Some(Transport::Tcp { flags, .. }) if flags & (TCP_SYN | TCP_ACK) == TCP_SYN);
+                            if connect && held != Verdict::Drop {
+                                SYN_REJECTS_SENT.fetch_add(1, Ordering::Relaxed);
+                            }
+                        }
+                    }
+                }
+                Outcome::Consumed
+            }
+        }
+    }
+
+    /// Verdict for a reply frame. None means it was swallowed (and counted as such); a
+    /// delivery is only counted once the inner write has taken the frame.
+    fn admit_inbound(&mut self, frame: &[u8]) -> Option<Delivery> {
+        let info = match PacketInfo::parse(frame) {
+            Some(info) if info.transport != Transport::Other => info,
+            _ => return Some(Delivery::Untracked),
+        };
+        match self.flows.classify_inbound(&info) {
+            Some((Action::Block, uid)) => {
+                INBOUND_DROPPED.fetch_add(1, Ordering::Relaxed);
+                capture::record(Direction::Inbound, uid, "DROP", frame);
+                None
+            }
+            Some((_, uid)) => Some(Delivery::Flow {
+                uid,
+                dns_answer: info.transport == Transport::Udp && info.src.port() == DNS_PORT,
+            }),
+            None if self.flows.unsolicited() == Unsolicited::Drop => {
+                INBOUND_UNSOLICITED.fetch_add(1, Ordering::Relaxed);
+                INBOUND_DROPPED.fetch_add(1, Ordering::Relaxed);
+                capture::record(Direction::Inbound, None, "UNSOLICITED_DROP", frame);
+                None
+            }
+            None => Some(Delivery::Unsolicited),
+        }
+    }
+}
+
+// What a reply frame that reached the apps is counted as
+#[derive(Clone, Copy)]
+enum Delivery {
+    // Not part of any flow we track (ICMP from the stack itself)
+    Untracked,
+    Flow { uid: Option<u32>, dns_answer: bool },
+    Unsolicited,
+}
+
+fn account_inbound(frame: &[u8], delivery: Delivery) {
+    RX_BYTES.fetch_add(frame.len() as u64, Ordering::Relaxed);
+    match delivery {
+        Delivery::Untracked => {}
+        Delivery::Flow { uid, dns_answer } => {
+            capture::record(Direction::Inbound, uid, "DELIVER", frame);
+            if dns_answer {
+                DNS_ANSWERS.fetch_add(1, Ordering::Relaxed);
+            }
+            if let (Some(uid), Ok(mut rx)) = (uid, UID_RX_BYTES.lock()) {
+                *rx.entry(uid).or_default() += frame.len() as u64;
+            }
+        }
+        Delivery::Unsolicited => {
+            INBOUND_UNSOLICITED.fetch_add(1, Ordering::Relaxed);
+            capture::record(Direction::Inbound, None, "UNSOLICITED", frame);
+        }
+    }
+}
+
+// Protocol counters, throughput and DNS queries for an outbound frame
+fn account_outbound(info: Option<&PacketInfo>, action: Action, len: usize) {
+    let counter = match info.map(|i| i.transport) {
+        Some(Transport::Tcp { .. }) => &TCP_COUNT,
+        Some(Transport::Udp) => &UDP_COUNT,
+        _ => &OTHER_COUNT,
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+    if action == Action::Block {
+        return;
+    }
+    TX_BYTES.fetch_add(len as u64, Ordering::Relaxed);
+    if info.is_some_and(|i| i.transport == Transport::Udp && i.dst.port() == DNS_PORT) {
+        DNS_QUERIES.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> AsyncRead for FilteredTun<T> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        for _ in 0..FRAME_BUDGET {
+            let unfilled = buf.initialize_unfilled();
+            let n = {
+                let mut frame = ReadBuf::new(unfilled);
+                match Pin::new(&mut this.inner).poll_read(cx, &mut frame) {
+                    Poll::Ready(Ok(())) => frame.filled().len(),
+                    other => return other,
+                }
+            };
+            if n == 0 {
+                // EOF
+                return Poll::Ready(Ok(()));
+            }
+            match this.route(&unfilled[..n], cx) {
+                Outcome::Deliver(len) => {
+                    buf.advance(len);
+                    return Poll::Ready(Ok(()));
+                }
+                Outcome::Consumed => continue,
+            }
+        }
+        // Still more to chew through: let other tasks run and come straight back
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for FilteredTun<T> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let Some(delivery) = this.admit_inbound(buf) else {
+            // Swallowed: the stack above sees a successful write, the app sees nothing
+            return Poll::Ready(Ok(buf.len()));
+        };
+        // A Pending write comes back with the same frame, so count it only once it is taken
+        let written = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = written {
+            account_inbound(&buf[..n], delivery);
+        }
+        written
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
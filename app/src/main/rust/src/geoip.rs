@@ -0,0 +1,257 @@
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+
+// --- GEOIP: OFFLINE MAXMIND DB READER ---
+// The app ships (or downloads) a GeoLite2/DB-IP country file and hands us its path. Only the
+// parts of the MaxMind DB format needed for a country lookup are implemented: the binary
+// search tree, and the data section types that can appear in a record.
+
+const METADATA_MARKER: &[u8] = b"\xAB\xCD\xEFMaxMind.com";
+const DATA_SEPARATOR: usize = 16;
+const MAX_DEPTH: u8 = 16;
+
+#[derive(Debug)]
+enum Value {
+    Str(String),
+    Uint(u64),
+    Map(BTreeMap<String, Value>),
+    Other,
+}
+
+impl Value {
+    fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Map(m) => m.get(key),
+            _ => None,
+        }
+    }
+
+    fn as_uint(&self) -> Option<u64> {
+        match self {
+            Value::Uint(v) => Some(*v),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+pub struct GeoIpDb {
+    buf: Vec<u8>,
+    node_count: usize,
+    record_size: usize,
+    ip_version: u64,
+    ipv4_start: usize,
+}
+
+impl GeoIpDb {
+    pub fn open(path: &str) -> Result<Self, String> {
+        let buf = std::fs::read(path).map_err(|e| format!("GEOIP_READ_FAILED: {}", e))?;
+        Self::from_bytes(buf)
+    }
+
+    pub fn from_bytes(buf: Vec<u8>) -> Result<Self, String> {
+        let marker = buf
+            .windows(METADATA_MARKER.len())
+            .rposition(|w| w == METADATA_MARKER)
+            .ok_or("GEOIP_NOT_MAXMIND_DB")?;
+        let meta_start = marker + METADATA_MARKER.len();
+        let (meta, _) = Decoder { buf: &buf[meta_start..] }.decode(0, 0)?;
+
+        let field = |k: &str| meta.get(k).and_then(Value::as_uint).ok_or(format!("GEOIP_BAD_METADATA: {}", k));
+        let node_count = field("node_count")? as usize;
+        let record_size = field("record_size")? as usize;
+        let ip_version = field("ip_version")?;
+        if ![24, 28, 32].contains(&record_size) {
+            return Err(format!("GEOIP_UNSUPPORTED_RECORD_SIZE: {}", record_size));
+        }
+        if ip_version != 4 && ip_version != 6 {
+            return Err(format!("GEOIP_BAD_METADATA: ip_version {}", ip_version));
+        }
+        let tree_size = node_count * record_size / 4;
+        if tree_size + DATA_SEPARATOR > marker {
+            return Err("GEOIP_TRUNCATED".to_string());
+        }
+
+        let mut db = GeoIpDb { buf, node_count, record_size, ip_version, ipv4_start: 0 };
+        // Every record must be a node, the empty marker or an offset into the data section.
+        // Lookups run on the routing path, so a bad file is turned away here, once
+        let data_len = marker - tree_size - DATA_SEPARATOR;
+        for node in 0..node_count {
+            for side in 0..2 {
+                let record = db.record(node, side)?;
+                if record > node_count && db.data_offset(record).is_none_or(|offset| offset >= data_len) {
+                    return Err(format!("GEOIP_BAD_RECORD: node {} -> {}", node, record));
+                }
+            }
+        }
+        if ip_version == 6 {
+            // IPv4 lives under ::/96 in an IPv6 tree
+            let mut node = 0;
+            for _ in 0..96 {
+                if node >= node_count {
+                    break;
+                }
+                node = db.record(node, 0)?;
+            }
+            db.ipv4_start = node;
+        }
+        Ok(db)
+    }
+
+    fn record(&self, node: usize, side: usize) -> Result<usize, String> {
+        let bytes = self.record_size / 4;
+        let off = node * bytes;
+        let b = self.buf.get(off..off + bytes).ok_or("GEOIP_TRUNCATED")?;
+        let be = |s: &[u8]| s.iter().fold(0usize, |acc, x| (acc << 8) | *x as usize);
+        Ok(match (self.record_size, side) {
+            (24, 0) => be(&b[0..3]),
+            (24, _) => be(&b[3..6]),
+            (28, 0) => ((b[3] as usize & 0xF0) << 20) | be(&b[0..3]),
+            (28, _) => ((b[3] as usize & 0x0F) << 24) | be(&b[4..7]),
+            (_, 0) => be(&b[0..4]),
+            _ => be(&b[4..8]),
+        })
+    }
+
+    // Data section offset a record points at; `None` inside the 16-byte separator
+    fn data_offset(&self, record: usize) -> Option<usize> {
+        record.checked_sub(self.node_count + DATA_SEPARATOR)
+    }
+
+    /// ISO 3166 country code for `ip`, falling back to the registered country.
+    pub fn country(&self, ip: IpAddr) -> Option<String> {
+        let (bits, width, mut node): (u128, u32, usize) = match ip {
+            IpAddr::V4(v4) => (u32::from(v4) as u128, 32, self.ipv4_start),
+            IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+                Some(v4) => (u32::from(v4) as u128, 32, self.ipv4_start),
+                None if self.ip_version == 6 => (u128::from(v6), 128, 0),
+                None => return None,
+            },
+        };
+        for i in (0..width).rev() {
+            if node >= self.node_count {
+                break;
+            }
+            node = self.record(node, ((bits >> i) & 1) as usize).ok()?;
+        }
+        if node <= self.node_count {
+            return None;
+        }
+
+        let data = &self.buf[self.node_count * self.record_size / 4 + DATA_SEPARATOR..];
+        let offset = self.data_offset(node)?;
+        let (record, _) = Decoder { buf: data }.decode(offset, 0).ok()?;
+        ["country", "registered_country"]
+            .iter()
+            .find_map(|k| record.get(k)?.get("iso_code")?.as_str().map(str::to_ascii_uppercase))
+    }
+}
+
+struct Decoder<'a> {
+    buf: &'a [u8],
+}
+
+impl Decoder<'_> {
+    fn bytes(&self, pos: usize, n: usize) -> Result<&[u8], String> {
+        self.buf.get(pos..pos + n).ok_or_else(|| "GEOIP_TRUNCATED".to_string())
+    }
+
+    fn uint(&self, pos: usize, n: usize) -> Result<u64, String> {
+        Ok(self.bytes(pos, n)?.iter().fold(0u64, |acc, b| (acc << 8) | *b as u64))
+    }
+
+    /// Decodes the field at `pos`, returning it and the offset just past it.
+    fn decode(&self, pos: usize, depth: u8) -> Result<(Value, usize), String> {
+        if depth > MAX_DEPTH {
+            return Err("GEOIP_TOO_DEEP".to_string());
+        }
+        let ctrl = *self.buf.get(pos).ok_or("GEOIP_TRUNCATED")?;
+        let mut pos = pos + 1;
+        let mut kind = ctrl >> 5;
+
+        if kind == 1 {
+            // Pointer: follow it, but carry on after the pointer itself
+            let ss = ((ctrl >> 3) & 0x3) as usize;
+            let vvv = (ctrl & 0x7) as u64;
+            let target = match ss {
+                0 => (vvv << 8) | self.uint(pos, 1)?,
+                1 => ((vvv << 16) | self.uint(pos, 2)?) + 2048,
+                2 => ((vvv << 24) | self.uint(pos, 3)?) + 526336,
+                _ => self.uint(pos, 4)?,
+            };
+            let (value, _) = self.decode(target as usize, depth + 1)?;
+            return Ok((value, pos + ss + 1));
+        }
+        if kind == 0 {
+            kind = 7u8.saturating_add(*self.buf.get(pos).ok_or("GEOIP_TRUNCATED")?);
+            pos += 1;
+        }
+
+        let mut size = (ctrl & 0x1f) as usize;
+        match size {
+            29 => {
+                size = 29 + self.uint(pos, 1)? as usize;
+                pos += 1;
+            }
+            30 => {
+                size = 285 + self.uint(pos, 2)? as usize;
+                pos += 2;
+            }
+            31 => {
+                size = 65821 + self.uint(pos, 3)? as usize;
+                pos += 3;
+            }
+            _ => {}
+        }
+
+        match kind {
+            2 => {
+                let s = std::str::from_utf8(self.bytes(pos, size)?).map_err(|_| "GEOIP_BAD_UTF8")?;
+                Ok((Value::Str(s.to_string()), pos + size))
+            }
+            5 | 6 | 9 | 10 => {
+                if size > 8 {
+                    // uint128 values are never needed here
+                    self.bytes(pos, size)?;
+                    return Ok((Value::Other, pos + size));
+                }
+                Ok((Value::Uint(self.uint(pos, size)?), pos + size))
+            }
+            3 => Ok((Value::Other, pos + 8)),
+            15 => Ok((Value::Other, pos + 4)),
+            4 | 8 => {
+                self.bytes(pos, size)?;
+                Ok((Value::Other, pos + size))
+            }
+            14 => Ok((Value::Other, pos)),
+            7 => {
+                let mut map = BTreeMap::new();
+                for _ in 0..size {
+                    let (key, next) = self.decode(pos, depth + 1)?;
+                    let key = match key {
+                        Value::Str(k) => k,
+                        _ => return Err("GEOIP_BAD_MAP_KEY".to_string()),
+                    };
+                    let (value, next) = self.decode(next, depth + 1)?;
+                    map.insert(key, value);
+                    pos = next;
+                }
+                Ok((Value::Map(map), pos))
+            }
+            11 => {
+                // Arrays (subdivisions, languages) are walked only to find where they end
+                for _ in 0..size {
+                    pos = self.decode(pos, depth + 1)?.1;
+                }
+                Ok((Value::Other, pos))
+            }
+            _ => Err(format!("GEOIP_UNKNOWN_TYPE: {}", kind)),
+        }
+    }
+}
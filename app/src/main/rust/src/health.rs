@@ -0,0 +1,213 @@
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use crate::common::*;
+use crate::engine::{EngineStats, Status};
+
+// --- HEALTH: WHAT getCoreHealth REPORTS ---
+// One snapshot of the engine for the UI. The old counters stay at the top level, so
+// readers of earlier documents keep working; `version` goes up whenever a field changes
+// meaning or goes away. Rates are worked out from samples taken on each snapshot, so they
+// cover the polls that fall within each window.
+
+pub const HEALTH_VERSION: u32 = 1;
+// Errors kept for the UI, oldest first
+const ERROR_HISTORY: usize = 16;
+// Throughput windows reported, in seconds
+const RATE_WINDOWS: [u64; 2] = [5, 60];
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ErrorRecord {
+    // Milliseconds since the Unix epoch
+    pub at_ms: u64,
+    pub code: &'static str,
+    pub detail: String,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct Throughput {
+    pub window_secs: u64,
+    // Bytes per second from the apps out to the network
+    pub tx_bps: u64,
+    // Bytes per second from the network back to the apps
+    pub rx_bps: u64,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ResolverStatus {
+    // `virtual` while tun2proxy answers DNS with fake addresses, `system` otherwise
+    pub mode: &'static str,
+    pub virtual_dns_pool: Option<String>,
+    pub queries: u64,
+    pub answers: u64,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct LockdownStats {
+    pub enabled: bool,
+    pub allowed_uids: usize,
+    // Packets the passive shield checked against the allowlist
+    pub allowed_packets: u64,
+    pub dropped_packets: u64,
+    // Flows the VPN path cut because their app is not on the allowlist
+    pub blocked_flows: u64,
+}
+
+/// The `getCoreHealth` document.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct HealthSnapshot {
+    pub version: u32,
+    #[serde(flatten)]
+    pub stats: EngineStats,
+    // Seconds since the running session started; 0 without one
+    pub uptime_secs: u64,
+    pub upstream: Option<String>,
+    pub throughput: Vec<Throughput>,
+    pub active_flows: u64,
+    pub resolver: ResolverStatus,
+    pub lockdown: LockdownStats,
+    pub errors: Vec<ErrorRecord>,
+}
+
+/// Session-level facts the counters cannot carry.
+#[derive(Default)]
+pub struct SessionHealth {
+    started: Option<Instant>,
+    upstream: Option<String>,
+    // The single shadowsocks server the session reaches, if it has exactly one
+    server: Option<String>,
+    virtual_dns_pool: Option<String>,
+    errors: VecDeque<ErrorRecord>,
+    rates: RateWindow,
+}
+
+/// Byte totals sampled over time; a rate is the difference across a window.
+#[derive(Default)]
+pub struct RateWindow {
+    samples: VecDeque<(Instant, u64, u64)>,
+}
+
+impl RateWindow {
+    pub fn sample(&mut self, now: Instant, tx: u64, rx: u64) {
+        self.samples.push_back((now, tx, rx));
+        // Keep one sample older than the widest window as its baseline
+        let widest = Duration::from_secs(RATE_WINDOWS[RATE_WINDOWS.len() - 1]);
+        while self.samples.len() > 2 && now.duration_since(self.samples[1].0) >= widest {
+            self.samples.pop_front();
+        }
+    }
+
+    pub fn rate(&self, window_secs: u64) -> Throughput {
+        let zero = Throughput { window_secs, tx_bps: 0, rx_bps: 0 };
+        let Some(&(now, tx, rx)) = self.samples.back() else { return zero };
+        // Latest sample at least a window old, or the oldest there is
+        let window = Duration::from_secs(window_secs);
+        let base = self
+            .samples
+            .iter()
+            .rev()
+            .find(|(at, _, _)| now.duration_since(*at) >= window)
+            .or(self.samples.front());
+        let Some(&(then, base_tx, base_rx)) = base else { return zero };
+        let elapsed = now.duration_since(then).as_secs_f64();
+        if elapsed <= 0.0 {
+            return zero;
+        }
+        Throughput {
+            window_secs,
+            tx_bps: (tx.saturating_sub(base_tx) as f64 / elapsed) as u64,
+            rx_bps: (rx.saturating_sub(base_rx) as f64 / elapsed) as u64,
+        }
+    }
+}
+
+fn with_session<R>(f: impl FnOnce(&mut SessionHealth) -> R) -> Option<R> {
+    HEALTH.lock().ok().map(|mut session| f(&mut session))
+}
+
+/// Marks the start of a session: uptime counts from here, the previous upstream is forgotten.
+pub fn session_started() {
+    with_session(|s| {
+        s.started = Some(Instant::now());
+        s.upstream = None;
+        s.server = None;
+        s.virtual_dns_pool = None;
+    });
+}
+
+pub fn set_upstream(label: String, server: Option<String>) {
+    with_session(|s| {
+        s.upstream = Some(label);
+        s.server = server;
+    });
+}
+
+/// Label of the session's upstream, once one is up.
+pub fn upstream() -> Option<String> {
+    with_session(|s| s.upstream.clone()).flatten()
+}
+
+/// Address of the session's shadowsocks server; `None` for a pool or any other upstream.
+pub fn server() -> Option<String> {
+    with_session(|s| s.server.clone()).flatten()
+}
+
+/// `Some(pool)` while tun2proxy answers DNS from that pool, `None` once it stops.
+pub fn set_virtual_dns(pool: Option<String>) {
+    with_session(|s| s.virtual_dns_pool = pool);
+}
+
+/// Keeps `code` for the UI's error history; the log line is still the caller's to write.
+pub fn record_error(code: &'static str, detail: impl Into<String>) {
+    let at_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+    let record = ErrorRecord { at_ms, code, detail: detail.into() };
+    with_session(|s| {
+        if s.errors.len() == ERROR_HISTORY {
+            s.errors.pop_front();
+        }
+        s.errors.push_back(record);
+    });
+}
+
+pub fn snapshot(stats: EngineStats) -> HealthSnapshot {
+    let tx = TX_BYTES.load(Ordering::Relaxed);
+    let rx = RX_BYTES.load(Ordering::Relaxed);
+    let running = matches!(stats.status, Status::Starting | Status::Running);
+    let (uptime_secs, upstream, pool, throughput, errors) = with_session(|s| {
+        s.rates.sample(Instant::now(), tx, rx);
+        let uptime = match s.started {
+            Some(started) if running => started.elapsed().as_secs(),
+            _ => 0,
+        };
+        let throughput = RATE_WINDOWS.iter().map(|w| s.rates.rate(*w)).collect();
+        // A session that ended mid-run leaves its pool set; only a running one is answering
+        let pool = s.virtual_dns_pool.clone().filter(|_| running);
+        (uptime, s.upstream.clone(), pool, throughput, s.errors.iter().cloned().collect())
+    })
+    .unwrap_or_default();
+    let allowed_uids = ALLOWED_UIDS.read().map(|a| a.len()).unwrap_or(0);
+
+    HealthSnapshot {
+        version: HEALTH_VERSION,
+        stats,
+        uptime_secs,
+        upstream,
+        throughput,
+        active_flows: if running { ACTIVE_FLOWS.load(Ordering::Relaxed) } else { 0 },
+        resolver: ResolverStatus {
+            mode: if pool.is_some() { "virtual" } else { "system" },
+            virtual_dns_pool: pool,
+            queries: DNS_QUERIES.load(Ordering::Relaxed),
+            answers: DNS_ANSWERS.load(Ordering::Relaxed),
+        },
+        lockdown: LockdownStats {
+            enabled: allowed_uids > 0,
+            allowed_uids,
+            allowed_packets: LOCKDOWN_ALLOWED.load(Ordering::Relaxed),
+            dropped_packets: LOCKDOWN_DROPPED.load(Ordering::Relaxed),
+            blocked_flows: LOCKDOWN_BLOCKED_FLOWS.load(Ordering::Relaxed),
+        },
+        errors,
+    }
+}
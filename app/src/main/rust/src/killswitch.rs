@@ -0,0 +1,159 @@
+use std::io;
+use std::net::IpAddr;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::io::unix::AsyncFd;
+use tokio::io::Interest;
+use tokio::sync::mpsc;
+use tun2proxy::CancellationToken;
+use crate::cidr::CidrTable;
+use crate::common::*;
+use crate::reject::reject_packet;
+use crate::routing::{current_block_mode, BlockMode};
+
+// --- KILL SWITCH: NOTHING LEAVES WHILE THE UPSTREAM IS DOWN ---
+// While the proxy is starting, restarting or dead, every packet on the TUN is read and
+// dropped unless its destination is on the explicit allowlist, in which case it takes the
+// direct path. Blocked packets get the same drop-or-reject treatment the routing policy
+// asks for. Counters prove to the UI that nothing slipped out.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Verdict {
+    // Upstream healthy (or kill switch off): normal routing applies
+    Pass,
+    // Upstream down, destination allowlisted: send direct
+    Allow,
+    // Upstream down: drop
+    Drop,
+}
+
+/// Replaces the kill-switch configuration. `allow` holds CIDRs or addresses separated by
+/// commas or newlines.
+pub fn configure(enabled: bool, allow: &str) -> Result<(), String> {
+    let mut table = CidrTable::default();
+    for cidr in allow.split([',', '\n']).map(str::trim).filter(|c| !c.is_empty()) {
+        table.insert_str(cidr, ())?;
+    }
+    if let Ok(mut current) = KILL_SWITCH_ALLOW.write() {
+        *current = Arc::new(table);
+    }
+    KILL_SWITCH.store(enabled, Ordering::SeqCst);
+    Ok(())
+}
+
+pub fn engaged() -> bool {
+    KILL_SWITCH.load(Ordering::Relaxed) && !UPSTREAM_UP.load(Ordering::Relaxed)
+}
+
+/// Records upstream health and logs kill-switch transitions.
+pub fn set_upstream_up(up: bool) {
+    let was_up = UPSTREAM_UP.swap(up, Ordering::SeqCst);
+    if was_up == up || !KILL_SWITCH.load(Ordering::Relaxed) {
+        return;
+    }
+    if up {
+        crate::log_to_java("VPN >> KILL_SWITCH: RELEASED");
+    } else {
+        LEAK_TRIPS.fetch_add(1, Ordering::Relaxed);
+        crate::log_to_java("VPN >> KILL_SWITCH: ENGAGED");
+    }
+}
+
+/// Verdict for an outbound packet of `len` bytes to `dst` on the normal data path.
+pub fn verdict(dst: Option<IpAddr>, len: usize) -> Verdict {
+    if !engaged() {
+        return Verdict::Pass;
+    }
+    hold(dst, len)
+}
+
+/// Verdict for a packet seen while the upstream is known to be down.
+pub fn hold(dst: Option<IpAddr>, len: usize) -> Verdict {
+    let allowed = match dst {
+        Some(ip) => KILL_SWITCH_ALLOW.read().map(|t| t.lookup(ip).is_some()).unwrap_or(false),
+        None => false,
+    };
+    if allowed {
+        LEAK_ALLOWED_PACKETS.fetch_add(1, Ordering::Relaxed);
+        Verdict::Allow
+    } else {
+        LEAK_BLOCKED_PACKETS.fetch_add(1, Ordering::Relaxed);
+        LEAK_BLOCKED_BYTES.fetch_add(len as u64, Ordering::Relaxed);
+        Verdict::Drop
+    }
+}
+
+fn destination(packet: &[u8]) -> Option<IpAddr> {
+    match packet.first()? >> 4 {
+        4 => {
+            let b: [u8; 4] = packet.get(16..20)?.try_into().ok()?;
+            Some(IpAddr::from(b))
+        }
+        6 => {
+            let b: [u8; 16] = packet.get(24..40)?.try_into().ok()?;
+            Some(IpAddr::from(b))
+        }
+        _ => None,
+    }
+}
+
+fn identity(fd: RawFd) -> Option<(libc::dev_t, libc::ino_t)> {
+    let mut st: libc::stat = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstat(fd, &mut st) } < 0 {
+        return None;
+    }
+    Some((st.st_dev, st.st_ino))
+}
+
+/// Reads packets off `fd` (through a dup, so the caller keeps ownership) and applies `hold`
+/// to each until `stop` fires or the fd goes away. Allowlisted packets go to `direct`.
+pub async fn drain(fd: RawFd, direct: Option<mpsc::Sender<Vec<u8>>>, stop: CancellationToken) -> io::Result<()> {
+    let dup = unsafe { libc::dup(fd) };
+    if dup < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let reader = AsyncFd::with_interest(unsafe { OwnedFd::from_raw_fd(dup) }, Interest::READABLE)?;
+    // Our dup keeps the interface alive, so notice Kotlin closing its fd by checking that
+    // the number still points at the same file
+    let ident = identity(dup);
+    let mut tick = tokio::time::interval(std::time::Duration::from_secs(1));
+    let mut buf = vec![0u8; 65535];
+    loop {
+        let mut guard = tokio::select! {
+            _ = stop.cancelled() => return Ok(()),
+            _ = tick.tick() => {
+                if identity(fd) != ident {
+                    return Ok(());
+                }
+                continue;
+            }
+            guard = reader.readable() => guard?,
+        };
+        let result = guard.try_io(|fd| {
+            let n = unsafe { libc::read(fd.as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+            if n < 0 { Err(io::Error::last_os_error()) } else { Ok(n as usize) }
+        });
+        match result {
+            Ok(Ok(0)) => return Ok(()),
+            Ok(Ok(n)) => match hold(destination(&buf[..n]), n) {
+                Verdict::Allow => {
+                    if let Some(direct) = &direct {
+                        let _ = direct.try_send(buf[..n].to_vec());
+                    }
+                }
+                _ if current_block_mode() == BlockMode::Reject => {
+                    if let Some(reply) = reject_packet(&buf[..n]) {
+                        let written = unsafe { libc::write(dup, reply.as_ptr() as *const libc::c_void, reply.len()) };
+                        if written > 0 {
+                            REJECTS_SENT.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                }
+                _ => {}
+            },
+            Ok(Err(e)) => return Err(e),
+            Err(_would_block) => continue,
+        }
+    }
+}
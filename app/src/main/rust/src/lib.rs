@@ -1,64 +1,111 @@
+mod acl;
+mod bridge;
+mod capture;
+mod cidr;
 mod common;
+mod config;
+mod diagnostics;
+mod energy;
+mod engine;
+mod filter;
+mod obfs;
+mod packet;
+mod procnet;
+mod geoip;
+mod health;
+mod killswitch;
+mod reject;
+mod routing;
+mod runtime;
+mod shield;
 mod vpn;
 mod stats;
+mod subscription;
+mod udp_relay;
+mod upstream;
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod tests;
 
-use jni::objects::{JClass, JString, JValue, GlobalRef, JLongArray};
+// Host-side API; the JNI functions below are a thin adapter over it
+pub use crate::capture::{CaptureConfig, CaptureSummary};
+pub use crate::common::SecureKey;
+pub use crate::config::{ApplyReport, ConfigError, EngineConfig, KillSwitchConfig, TunConfig};
+pub use crate::diagnostics::{
+    ConnectReport, DiagnosticsConfig, DiagnosticsReport, DnsProbe, DnsReport, ThroughputProbe, ThroughputReport, TlsProbe,
+    TlsReport, TunnelProbe, TunnelReport,
+};
+pub use crate::energy::{EnergyBreakdown, EnergyInputs, EnergyModel, EnergyReport};
+pub use crate::engine::{Engine, EngineStats, KillSwitchStats, Status};
+pub use crate::health::{ErrorRecord, HealthSnapshot, LockdownStats, ResolverStatus, Throughput};
+pub use crate::runtime::{Flavor, RuntimeConfig};
+
+use jni::objects::{JClass, JString, JLongArray};
 use jni::{JNIEnv, JavaVM};
 use jni::sys::{jstring, jlong, jint, jboolean};
 use std::sync::atomic::Ordering;
+use crate::bridge::BridgeError;
 use crate::common::*;
 
-static mut JVM: Option<JavaVM> = None;
-static mut CLASS_REF: Option<GlobalRef> = None;
-
 #[no_mangle]
-pub extern "system" fn JNI_OnLoad(vm: JavaVM, _reserved: std::ffi::c_void) -> jint {
-    unsafe {
-        if let Ok(mut env) = vm.get_env() {
-            if let Ok(class) = env.find_class("com/example/igy/IgyNetwork") {
-                if let Ok(global_ref) = env.new_global_ref(class) {
-                    CLASS_REF = Some(global_ref);
-                }
-            }
-        }
-        JVM = Some(vm);
-    }
-    jni::sys::JNI_VERSION_1_6
+pub extern "system" fn JNI_OnLoad(vm: JavaVM, _reserved: *mut std::ffi::c_void) -> jint {
+    let Ok(mut env) = vm.get_env() else { return jni::sys::JNI_VERSION_1_6 };
+    bridge::entry(&mut env, jni::sys::JNI_VERSION_1_6, bridge::init)
 }
 
 pub fn log_to_java(msg: &str) {
-    unsafe {
-        if let Some(ref vm) = JVM {
-            // Attach the current thread if not already attached
-            if let Ok(mut env) = vm.attach_current_thread() {
-                if let Some(ref class_ref) = CLASS_REF {
-                    if let Ok(msg_jstring) = env.new_string(msg) {
-                        let _ = env.call_static_method(
-                            class_ref,
-                            "nativeLog",
-                            "(Ljava/lang/String;)V",
-                            &[JValue::from(&msg_jstring)],
-                        );
-                    }
-                }
-                // Always clear exceptions to prevent the JNI boundary from crashing
-                if env.exception_check().unwrap_or(false) {
-                    let _ = env.exception_clear();
-                }
-            }
+    bridge::log(msg);
+    engine::forward_log(msg);
+}
+
+// Single-value setters edit one field of the engine config; a rejected value is thrown back
+fn update_config<F: FnOnce(&mut config::EngineConfig)>(edit: F) -> Result<config::ApplyReport, BridgeError> {
+    config::update(edit).map_err(|errors| {
+        for e in &errors {
+            crate::log_to_java(&format!("VPN >> CONFIG_REJECTED: {} {}", e.field, e.reason));
         }
-    }
+        BridgeError::IllegalArgument(first_error(errors))
+    })
+}
+
+fn first_error(errors: Vec<config::ConfigError>) -> String {
+    errors.into_iter().next().map(|e| e.reason).unwrap_or_default()
+}
+
+#[no_mangle]
+pub extern "system" fn Java_com_example_igy_IgyNetwork_applyConfig(
+    mut env: JNIEnv,
+    _class: JClass,
+    config_json: JString,
+) -> jstring {
+    bridge::entry(&mut env, std::ptr::null_mut(), |env| {
+        let json = bridge::string(env, &config_json)?;
+        let result = match config::EngineConfig::from_json(&json).and_then(config::apply) {
+            Ok(report) => {
+                crate::log_to_java(&format!(
+                    "VPN >> CONFIG_APPLIED: V{} {}_CHANGED{}",
+                    report.version,
+                    report.changed.len(),
+                    if report.restart_required { " (RESTART_REQUIRED)" } else { "" }
+                ));
+                serde_json::json!({ "ok": true, "report": report }).to_string()
+            }
+            Err(errors) => {
+                crate::log_to_java(&format!("VPN >> CONFIG_REJECTED: {}_ERRORS", errors.len()));
+                serde_json::json!({ "ok": false, "errors": errors }).to_string()
+            }
+        };
+        bridge::new_string(env, result)
+    })
 }
 
 #[no_mangle]
 pub extern "system" fn Java_com_example_igy_IgyNetwork_toggleStealthMode(
-    _env: JNIEnv,
+    mut env: JNIEnv,
     _class: JClass,
     enabled: jboolean,
 ) {
-    STEALTH_MODE.store(enabled != 0, Ordering::Relaxed);
+    bridge::entry(&mut env, (), |_| update_config(|c| c.stealth = enabled != 0).map(drop))
 }
 
 #[no_mangle]
@@ -67,11 +114,56 @@ pub extern "system" fn Java_com_example_igy_IgyNetwork_setOutlineKey(
     _class: JClass,
     key: JString,
 ) {
-    if let Ok(key_str) = env.get_string(&key) {
-        if let Ok(mut k) = OUTLINE_KEY.write() {
-            *k = SecureKey { key: key_str.into() };
-        }
-    }
+    bridge::entry(&mut env, (), |env| {
+        let key = SecureKey { key: bridge::string(env, &key)? };
+        update_config(|c| c.key = key).map(drop)
+    })
+}
+
+#[no_mangle]
+pub extern "system" fn Java_com_example_igy_IgyNetwork_setServerSubscription(
+    mut env: JNIEnv,
+    _class: JClass,
+    content: JString,
+) -> jstring {
+    bridge::entry(&mut env, std::ptr::null_mut(), |env| {
+        let content = bridge::string(env, &content)?;
+        let report = match subscription::parse_document(&content) {
+            Ok(sub) => {
+                let report = sub.report();
+                crate::log_to_java(&format!(
+                    "VPN >> SUBSCRIPTION: {}_SERVERS ({}_REJECTED)",
+                    sub.servers.len(),
+                    sub.rejected.len()
+                ));
+                // Keep the previous pool if nothing in the document was usable
+                if !sub.servers.is_empty() {
+                    if let Ok(mut pool) = SERVER_POOL.write() {
+                        *pool = sub.servers;
+                    }
+                }
+                report
+            }
+            Err(e) => {
+                crate::log_to_java(&format!("VPN >> SUBSCRIPTION_ERR: {}", e));
+                serde_json::json!({ "accepted": 0, "error": e }).to_string()
+            }
+        };
+        bridge::new_string(env, report)
+    })
+}
+
+#[no_mangle]
+pub extern "system" fn Java_com_example_igy_IgyNetwork_setUdpGateway(
+    mut env: JNIEnv,
+    _class: JClass,
+    addr: JString,
+) {
+    bridge::entry(&mut env, (), |env| {
+        let addr = bridge::string(env, &addr)?;
+        let gateway = Some(addr.trim().to_string()).filter(|a| !a.is_empty());
+        update_config(|c| c.udp_gateway = gateway).map(drop)
+    })
 }
 
 #[no_mangle]
@@ -80,18 +172,14 @@ pub extern "system" fn Java_com_example_igy_IgyNetwork_setAllowedDomains(
     _class: JClass,
     domains: JString,
 ) {
-    if let Ok(domains_str) = env.get_string(&domains) {
-        let domains_vec: Vec<String> = domains_str
-            .to_str()
-            .unwrap_or("")
+    bridge::entry(&mut env, (), |env| {
+        let domains_vec: Vec<String> = bridge::string(env, &domains)?
             .split(',')
             .map(|s| s.trim().to_string())
             .filter(|s| !s.is_empty())
             .collect();
-        if let Ok(mut allowed) = ALLOWED_DOMAINS.write() {
-            *allowed = domains_vec;
-        }
-    }
+        update_config(|c| c.allowed_domains = domains_vec).map(drop)
+    })
 }
 
 #[no_mangle]
@@ -100,97 +188,340 @@ pub extern "system" fn Java_com_example_igy_IgyNetwork_setAllowedUids(
     _class: JClass,
     uids: JLongArray,
 ) {
-    if let Ok(len) = env.get_array_length(&uids) {
-        let mut uids_vec = vec![0i64; len as usize];
-        if let Ok(_) = env.get_long_array_region(&uids, 0, &mut uids_vec) {
-            let uids_u32: Vec<u32> = uids_vec.into_iter().map(|uid| uid as u32).collect();
-            if let Ok(mut allowed) = ALLOWED_UIDS.write() {
-                *allowed = uids_u32;
-                crate::log_to_java(&format!("SHIELD >> SYNC_FOCUS_LIST: {}_UIDS", len));
+    bridge::entry(&mut env, (), |env| {
+        let uids = bridge::uids(env, &uids)?;
+        let len = uids.len();
+        update_config(|c| c.allowed_uids = uids)?;
+        crate::log_to_java(&format!("SHIELD >> SYNC_FOCUS_LIST: {}_UIDS", len));
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub extern "system" fn Java_com_example_igy_IgyNetwork_setRoutingPolicy(
+    mut env: JNIEnv,
+    _class: JClass,
+    policy_json: JString,
+) -> jstring {
+    bridge::entry(&mut env, std::ptr::null_mut(), |env| {
+        let json = bridge::string(env, &policy_json)?;
+        let result = match routing::RoutingPolicy::from_json(&json) {
+            Ok(policy) => {
+                let geoip_missing = policy.has_country_rules() && GEOIP_DB.read().map(|db| db.is_none()).unwrap_or(true);
+                let document = serde_json::from_str::<serde_json::Value>(&json).ok();
+                update_config(|c| c.routing = document)?;
+                crate::log_to_java(&format!("ROUTE >> POLICY_APPLIED: DEFAULT_{:?}", policy.default).to_uppercase());
+                if geoip_missing {
+                    // Country rules stay inert until setGeoIpDatabase succeeds
+                    r#"{"ok":true,"warning":"GEOIP_DB_NOT_LOADED"}"#.to_string()
+                } else {
+                    r#"{"ok":true}"#.to_string()
+                }
             }
-        }
-    }
+            Err(e) => {
+                crate::log_to_java(&format!("ROUTE >> {}", e));
+                serde_json::json!({ "ok": false, "error": e }).to_string()
+            }
+        };
+        bridge::new_string(env, result)
+    })
+}
+
+#[no_mangle]
+pub extern "system" fn Java_com_example_igy_IgyNetwork_setAcl(
+    mut env: JNIEnv,
+    _class: JClass,
+    acl_text: JString,
+    cache_dir: JString,
+) -> jstring {
+    bridge::entry(&mut env, std::ptr::null_mut(), |env| {
+        let text = bridge::string(env, &acl_text)?;
+        let dir = bridge::string(env, &cache_dir)?;
+        let result = match acl::install(&text, &dir) {
+            Ok(summary) => {
+                crate::log_to_java(&format!(
+                    "VPN >> ACL_SET: {}_BYPASS_{}_PROXY ({})",
+                    summary.bypass_rules, summary.proxy_rules, summary.mode
+                ));
+                serde_json::json!({ "ok": true, "summary": summary }).to_string()
+            }
+            Err(errors) => {
+                crate::log_to_java(&format!("VPN >> ACL_REJECTED: {}_ERRORS", errors.len()));
+                serde_json::json!({ "ok": false, "errors": errors }).to_string()
+            }
+        };
+        bridge::new_string(env, result)
+    })
+}
+
+#[no_mangle]
+pub extern "system" fn Java_com_example_igy_IgyNetwork_setGeoIpDatabase(
+    mut env: JNIEnv,
+    _class: JClass,
+    path: JString,
+) -> jstring {
+    bridge::entry(&mut env, std::ptr::null_mut(), |env| {
+        let path = bridge::string(env, &path)?;
+        let result = match routing::install_geoip(path.trim()) {
+            Ok(()) => {
+                crate::log_to_java(if path.trim().is_empty() { "ROUTE >> GEOIP_UNLOADED" } else { "ROUTE >> GEOIP_LOADED" });
+                r#"{"ok":true}"#.to_string()
+            }
+            Err(e) => {
+                crate::log_to_java(&format!("ROUTE >> {}", e));
+                serde_json::json!({ "ok": false, "error": e }).to_string()
+            }
+        };
+        bridge::new_string(env, result)
+    })
+}
+
+#[no_mangle]
+pub extern "system" fn Java_com_example_igy_IgyNetwork_setKillSwitch(
+    mut env: JNIEnv,
+    _class: JClass,
+    enabled: jboolean,
+    allow_cidrs: JString,
+) -> jstring {
+    bridge::entry(&mut env, std::ptr::null_mut(), |env| {
+        let allow = bridge::string(env, &allow_cidrs)?;
+        let allow: Vec<String> = allow.split([',', '\n']).map(str::trim).filter(|c| !c.is_empty()).map(String::from).collect();
+        // The health probe target is only set through applyConfig
+        let update = |c: &mut config::EngineConfig| {
+            c.kill_switch.enabled = enabled != 0;
+            c.kill_switch.allow = allow;
+        };
+        let result = match config::update(update).map_err(first_error) {
+            Ok(_) => {
+                crate::log_to_java(if enabled != 0 { "VPN >> KILL_SWITCH: ENABLED" } else { "VPN >> KILL_SWITCH: DISABLED" });
+                r#"{"ok":true}"#.to_string()
+            }
+            Err(e) => {
+                crate::log_to_java(&format!("VPN >> KILL_SWITCH_{}", e));
+                serde_json::json!({ "ok": false, "error": e }).to_string()
+            }
+        };
+        bridge::new_string(env, result)
+    })
+}
+
+#[no_mangle]
+pub extern "system" fn Java_com_example_igy_IgyNetwork_setRuntimeConfig(
+    mut env: JNIEnv,
+    _class: JClass,
+    config_json: JString,
+) -> jstring {
+    bridge::entry(&mut env, std::ptr::null_mut(), |env| {
+        let json = bridge::string(env, &config_json)?;
+        let parsed = runtime::RuntimeConfig::from_json(&json)
+            .and_then(|rt| config::update(|c| c.runtime = rt.clone()).map(|_| rt).map_err(first_error));
+        let result = match parsed {
+            Ok(config) => {
+                crate::log_to_java(&format!(
+                    "VPN >> RUNTIME_CONFIG: {:?} WORKERS_{} BLOCKING_{}",
+                    config.flavor, config.worker_threads, config.max_blocking_threads
+                ).to_uppercase());
+                r#"{"ok":true}"#.to_string()
+            }
+            Err(e) => {
+                crate::log_to_java(&format!("VPN >> {}", e));
+                serde_json::json!({ "ok": false, "error": e }).to_string()
+            }
+        };
+        bridge::new_string(env, result)
+    })
 }
 
 #[no_mangle]
 pub extern "system" fn Java_com_example_igy_IgyNetwork_getCoreHealth(
-    env: JNIEnv,
-    _class: JClass,
-) -> jstring {
-    let status = CORE_STATUS.load(Ordering::SeqCst);
-    let status_str = match status {
-        0 => "STOPPED",
-        1 => "STARTING",
-        2 => "RUNNING",
-        3 => "ERROR",
-        _ => "UNKNOWN",
-    };
-    
-    let stats = format!(
-        r#"{{"status":"{}","tcp":{},"udp":{},"other":{},"bytes":{},"port":{}}}"#,
-        status_str,
-        TCP_COUNT.load(Ordering::Relaxed),
-        UDP_COUNT.load(Ordering::Relaxed),
-        OTHER_COUNT.load(Ordering::Relaxed),
-        BYTES_PROCESSED.load(Ordering::Relaxed),
-        PROXY_PORT.load(Ordering::Relaxed)
-    );
-    
-    env.new_string(stats).map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut())
+    mut env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    bridge::entry(&mut env, std::ptr::null_mut(), |env| {
+        let stats = serde_json::to_string(&Engine::current().health()).unwrap_or_default();
+        bridge::new_string(env, stats)
+    })
+}
+
+#[no_mangle]
+pub extern "system" fn Java_com_example_igy_IgyNetwork_getTunConfig(
+    mut env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    bridge::entry(&mut env, std::ptr::null_mut(), |env| {
+        // {"mtu":1280,"ipv4":"10.0.0.1/24","ipv6":"fd00:1::1/64",...}: what the VpnService builder sets up
+        let tun = serde_json::to_string(&Engine::current().config().tun).unwrap_or_default();
+        bridge::new_string(env, tun)
+    })
+}
+
+#[no_mangle]
+pub extern "system" fn Java_com_example_igy_IgyNetwork_getUidTraffic(
+    mut env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    bridge::entry(&mut env, std::ptr::null_mut(), |env| {
+        let mut traffic: Vec<(u32, u64)> = UID_RX_BYTES
+            .lock()
+            .map(|rx| rx.iter().map(|(uid, bytes)| (*uid, *bytes)).collect())
+            .unwrap_or_default();
+        traffic.sort_unstable();
+        // [{"uid":10123,"rx_bytes":4096}]: bytes the tunnel delivered to each app
+        let list: Vec<_> = traffic
+            .into_iter()
+            .map(|(uid, rx_bytes)| serde_json::json!({ "uid": uid, "rx_bytes": rx_bytes }))
+            .collect();
+        bridge::new_string(env, serde_json::Value::Array(list).to_string())
+    })
 }
 
 #[no_mangle]
 pub extern "system" fn Java_com_example_igy_IgyNetwork_runVpnLoop(
-    _env: JNIEnv,
+    mut env: JNIEnv,
     _class: JClass,
     fd: jint,
 ) {
-    let _ = std::panic::catch_unwind(|| {
-        vpn::start_vpn_loop(fd);
-    });
+    bridge::entry(&mut env, (), |_| {
+        Engine::current().run(fd);
+        Ok(())
+    })
 }
 
 #[no_mangle]
 pub extern "system" fn Java_com_example_igy_IgyNetwork_runPassiveShield(
-    _env: JNIEnv,
+    mut env: JNIEnv,
     _class: JClass,
     fd: jint,
 ) {
-    let _ = std::panic::catch_unwind(|| {
-        TOKIO_RT.block_on(vpn::run_passive_shield_internal(fd));
-    });
+    bridge::entry(&mut env, (), |_| {
+        Engine::current().run_shield(fd);
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub extern "system" fn Java_com_example_igy_IgyNetwork_stopEngine(
+    mut env: JNIEnv,
+    _class: JClass,
+) {
+    bridge::entry(&mut env, (), |_| {
+        Engine::current().stop();
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub extern "system" fn Java_com_example_igy_IgyNetwork_startCapture(
+    mut env: JNIEnv,
+    _class: JClass,
+    config_json: JString,
+) -> jstring {
+    bridge::entry(&mut env, std::ptr::null_mut(), |env| {
+        let json = bridge::string(env, &config_json)?;
+        let result = match capture::CaptureConfig::from_json(&json) {
+            Ok(config) => {
+                crate::log_to_java(&format!("VPN >> CAPTURE_STARTED: {} BYTES, {}S", config.max_bytes, config.max_seconds));
+                Engine::current().start_capture(config);
+                r#"{"ok":true}"#.to_string()
+            }
+            Err(e) => serde_json::json!({ "ok": false, "error": e }).to_string(),
+        };
+        bridge::new_string(env, result)
+    })
+}
+
+#[no_mangle]
+pub extern "system" fn Java_com_example_igy_IgyNetwork_stopCapture(
+    mut env: JNIEnv,
+    _class: JClass,
+    path: JString,
+) -> jstring {
+    bridge::entry(&mut env, std::ptr::null_mut(), |env| {
+        let path = bridge::string(env, &path)?;
+        let target = (!path.is_empty()).then(|| std::path::Path::new(&path));
+        let result = match Engine::current().stop_capture(target) {
+            Ok(summary) => {
+                crate::log_to_java(&format!("VPN >> CAPTURE_SAVED: {} PACKETS", summary.packets));
+                serde_json::json!({ "ok": true, "summary": summary }).to_string()
+            }
+            Err(e) => serde_json::json!({ "ok": false, "error": e }).to_string(),
+        };
+        bridge::new_string(env, result)
+    })
 }
 
 #[no_mangle]
 pub extern "system" fn Java_com_example_igy_IgyNetwork_setBandwidthLimit(
-    _env: JNIEnv,
+    mut env: JNIEnv,
     _class: JClass,
     limit_mbps: jint,
 ) {
-    let bytes_per_sec = (limit_mbps as u64) * 1024 * 1024 / 8;
-    BANDWIDTH_LIMIT.store(bytes_per_sec, Ordering::Relaxed);
+    bridge::entry(&mut env, (), |_| {
+        let limit = u32::try_from(limit_mbps)
+            .map_err(|_| BridgeError::IllegalArgument(format!("OUT_OF_RANGE: {}", limit_mbps)))?;
+        update_config(|c| c.bandwidth_limit_mbps = limit).map(drop)
+    })
 }
 
 #[no_mangle]
 pub extern "system" fn Java_com_example_igy_IgyNetwork_getNativeBlockedCount(
-    _env: JNIEnv,
+    mut env: JNIEnv,
     _class: JClass,
 ) -> jlong {
-    (TCP_COUNT.load(Ordering::Relaxed) + 
-     UDP_COUNT.load(Ordering::Relaxed) + 
-     OTHER_COUNT.load(Ordering::Relaxed)) as jlong
+    bridge::entry(&mut env, 0, |_| {
+        Ok((TCP_COUNT.load(Ordering::Relaxed) +
+            UDP_COUNT.load(Ordering::Relaxed) +
+            OTHER_COUNT.load(Ordering::Relaxed)) as jlong)
+    })
 }
 
 #[no_mangle]
 pub extern "system" fn Java_com_example_igy_IgyNetwork_getEnergySavings(
-    env: JNIEnv,
+    mut env: JNIEnv,
+    _class: JClass,
+) -> jstring {
+    bridge::entry(&mut env, std::ptr::null_mut(), |env| {
+        let report = serde_json::to_string(&Engine::current().energy_savings()).unwrap_or_default();
+        bridge::new_string(env, report)
+    })
+}
+
+#[no_mangle]
+pub extern "system" fn Java_com_example_igy_IgyNetwork_runDiagnostics(
+    mut env: JNIEnv,
+    _class: JClass,
+    config_json: JString,
+) -> jstring {
+    bridge::entry(&mut env, std::ptr::null_mut(), |env| {
+        let json = bridge::string(env, &config_json)?;
+        let result = match DiagnosticsConfig::from_json(&json).and_then(|c| Engine::current().diagnose(&c)) {
+            Ok(report) => serde_json::json!({ "ok": true, "report": report }).to_string(),
+            Err(e) => serde_json::json!({ "ok": false, "error": e }).to_string(),
+        };
+        bridge::new_string(env, result)
+    })
+}
+
+#[no_mangle]
+pub extern "system" fn Java_com_example_igy_IgyNetwork_cancelDiagnostics(
+    mut env: JNIEnv,
+    _class: JClass,
+) {
+    bridge::entry(&mut env, (), |_| {
+        Engine::current().cancel_diagnostics();
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub extern "system" fn Java_com_example_igy_IgyNetwork_getTunnelRanking(
+    mut env: JNIEnv,
     _class: JClass,
 ) -> jstring {
-    let bytes = BYTES_PROCESSED.load(Ordering::Relaxed);
-    // Real metric: CPU cycles saved by not context switching to JVM for every packet
-    let mah_saved = (bytes as f64 / 1024.0 / 1024.0) * 0.12; 
-    let result = format!("{:.2} mAh", mah_saved);
-    env.new_string(result).map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut())
+    bridge::entry(&mut env, std::ptr::null_mut(), |env| {
+        let ranking = serde_json::to_string(&Engine::current().tunnel_ranking()).unwrap_or_default();
+        bridge::new_string(env, ranking)
+    })
 }
 
 #[no_mangle]
@@ -200,19 +531,17 @@ pub extern "system" fn Java_com_example_igy_IgyNetwork_kickDevice(
     target_ip: JString,
     _target_mac: JString,
 ) -> jboolean {
-    if let Ok(ip) = env.get_string(&target_ip) {
-        let ip_str: String = ip.into();
-        TOKIO_RT.spawn(async move {
+    bridge::entry(&mut env, 0, |env| {
+        let ip_str = bridge::string(env, &target_ip)?;
+        runtime::spawn(async move {
             let addr = format!("{}:80", ip_str);
             for _ in 0..500 {
                 let _ = tokio::net::TcpStream::connect(&addr).await;
                 tokio::time::sleep(std::time::Duration::from_millis(5)).await;
             }
         });
-        1
-    } else {
-        0
-    }
+        Ok(1)
+    })
 }
 
 #[no_mangle]
@@ -221,7 +550,8 @@ pub extern "system" fn Java_com_example_igy_IgyNetwork_measureNetworkStats(
     _class: JClass,
     target_ip: JString,
 ) -> jstring {
-    let ip: String = env.get_string(&target_ip).map(|s| s.into()).unwrap_or_default();
-    let result = stats::measure_stats(ip);
-    env.new_string(result).map(|s| s.into_raw()).unwrap_or(std::ptr::null_mut())
+    bridge::entry(&mut env, std::ptr::null_mut(), |env| {
+        let ip = bridge::string(env, &target_ip)?;
+        bridge::new_string(env, stats::measure_stats(ip))
+    })
 }
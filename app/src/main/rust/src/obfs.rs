@@ -0,0 +1,393 @@
+use base64::Engine;
+use std::io;
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+// --- STEALTH: IN-PROCESS SIMPLE-OBFS (SIP003) TRANSPORT ---
+// Android cannot ship the obfs-local binary, so the framing runs inside the engine. ss-local
+// connects to a loopback listener here, which wraps each stream in HTTP or TLS framing
+// compatible with simple-obfs' obfs-server before it leaves the device.
+
+const MAX_RECORD: usize = 16 * 1024;
+const MAX_HEADER: usize = 8 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ObfsMode {
+    Http,
+    Tls,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ObfsConfig {
+    pub mode: ObfsMode,
+    pub host: String,
+}
+
+impl ObfsConfig {
+    /// Parses SIP003 `plugin_opts`, e.g. `obfs=tls;obfs-host=www.bing.com`.
+    pub fn from_plugin_opts(opts: Option<&str>) -> Result<Self, String> {
+        let mut mode = ObfsMode::Http;
+        let mut host = "www.bing.com".to_string();
+        for opt in opts.unwrap_or("").split(';').filter(|o| !o.is_empty()) {
+            let (key, value) = opt.split_once('=').unwrap_or((opt, ""));
+            match key {
+                "obfs" => {
+                    mode = match value {
+                        "http" => ObfsMode::Http,
+                        "tls" => ObfsMode::Tls,
+                        other => return Err(format!("UNSUPPORTED_OBFS_MODE: {}", other)),
+                    }
+                }
+                "obfs-host" if !value.is_empty() => host = value.to_string(),
+                // obfs-uri and friends only matter to the server side
+                _ => {}
+            }
+        }
+        Ok(ObfsConfig { mode, host })
+    }
+}
+
+/// Binds a loopback listener that relays every accepted stream to `remote` with obfs framing.
+pub async fn spawn_obfs_local(cfg: ObfsConfig, remote: String) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let local_addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((inbound, _)) = listener.accept().await {
+            let cfg = cfg.clone();
+            let remote = remote.clone();
+            tokio::spawn(async move {
+                let outbound = match TcpStream::connect(&remote).await {
+                    Ok(s) => s,
+                    Err(e) => {
+                        crate::log_to_java(&format!("STEALTH >> CONNECT_ERR: {}", e));
+                        return;
+                    }
+                };
+                let _ = inbound.set_nodelay(true);
+                let _ = outbound.set_nodelay(true);
+                if let Err(e) = relay_client(inbound, outbound, &cfg).await {
+                    if e.kind() != io::ErrorKind::UnexpectedEof {
+                        crate::log_to_java(&format!("STEALTH >> RELAY_ERR: {}", e));
+                    }
+                }
+            });
+        }
+    });
+    Ok(local_addr)
+}
+
+/// Server half of the transport: strips obfs framing and forwards plain streams to `target`.
+/// Lets the client be exercised against the same plugin without an external obfs-server.
+#[cfg(test)]
+pub async fn spawn_obfs_server(mode: ObfsMode, bind: &str, target: String) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(bind).await?;
+    let local_addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((inbound, _)) = listener.accept().await {
+            let target = target.clone();
+            tokio::spawn(async move {
+                if let Ok(outbound) = TcpStream::connect(&target).await {
+                    let _ = relay_server(inbound, outbound, mode).await;
+                }
+            });
+        }
+    });
+    Ok(local_addr)
+}
+
+async fn relay_client(mut plain: TcpStream, mut obfs: TcpStream, cfg: &ObfsConfig) -> io::Result<()> {
+    // simple-obfs piggybacks the first payload on the handshake, so wait for it
+    let mut first = vec![0u8; MAX_RECORD];
+    let n = plain.read(&mut first).await?;
+    if n == 0 {
+        return Ok(());
+    }
+    first.truncate(n);
+
+    match cfg.mode {
+        ObfsMode::Http => {
+            obfs.write_all(&http_request(&cfg.host, &first)).await?;
+            let (head, rest) = read_http_head(&mut obfs).await?;
+            if !head.starts_with(b"HTTP/1.1 101") {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "OBFS_HTTP_REJECTED"));
+            }
+            plain.write_all(&rest).await?;
+            tokio::io::copy_bidirectional(&mut plain, &mut obfs).await?;
+        }
+        ObfsMode::Tls => {
+            obfs.write_all(&tls_client_hello(&cfg.host, &first)).await?;
+            // ServerHello, ChangeCipherSpec and the (fake) encrypted Finished
+            for _ in 0..3 {
+                read_tls_record(&mut obfs).await?;
+            }
+            let (obfs_r, mut obfs_w) = obfs.split();
+            let (mut plain_r, mut plain_w) = plain.split();
+            let upstream = async {
+                let mut hello_done = false;
+                let mut buf = vec![0u8; MAX_RECORD];
+                loop {
+                    let n = plain_r.read(&mut buf).await?;
+                    if n == 0 {
+                        return obfs_w.shutdown().await;
+                    }
+                    let mut out = Vec::with_capacity(n + 64);
+                    if !hello_done {
+                        out.extend_from_slice(&tls_client_finish());
+                        hello_done = true;
+                    }
+                    out.extend_from_slice(&tls_app_data(&buf[..n]));
+                    obfs_w.write_all(&out).await?;
+                }
+            };
+            let downstream = unwrap_tls_records(obfs_r, &mut plain_w);
+            tokio::try_join!(upstream, downstream)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+async fn relay_server(mut obfs: TcpStream, mut plain: TcpStream, mode: ObfsMode) -> io::Result<()> {
+    match mode {
+        ObfsMode::Http => {
+            let (head, rest) = read_http_head(&mut obfs).await?;
+            if !head.starts_with(b"GET ") {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "OBFS_HTTP_BAD_REQUEST"));
+            }
+            obfs.write_all(&http_response()).await?;
+            plain.write_all(&rest).await?;
+            tokio::io::copy_bidirectional(&mut obfs, &mut plain).await?;
+        }
+        ObfsMode::Tls => {
+            let (content_type, hello) = read_tls_record(&mut obfs).await?;
+            let ticket = match content_type {
+                0x16 => parse_session_ticket(&hello)
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "OBFS_TLS_NO_TICKET"))?,
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "OBFS_TLS_BAD_HELLO")),
+            };
+            obfs.write_all(&tls_server_hello(&hello)).await?;
+            plain.write_all(&ticket).await?;
+
+            let (obfs_r, mut obfs_w) = obfs.split();
+            let (mut plain_r, mut plain_w) = plain.split();
+            let downstream = async {
+                let mut buf = vec![0u8; MAX_RECORD];
+                loop {
+                    let n = plain_r.read(&mut buf).await?;
+                    if n == 0 {
+                        return obfs_w.shutdown().await;
+                    }
+                    obfs_w.write_all(&tls_app_data(&buf[..n])).await?;
+                }
+            };
+            let upstream = unwrap_tls_records(obfs_r, &mut plain_w);
+            tokio::try_join!(upstream, downstream)?;
+        }
+    }
+    Ok(())
+}
+
+// --- HTTP FRAMING ---
+
+fn http_request(host: &str, payload: &[u8]) -> Vec<u8> {
+    let key = base64::engine::general_purpose::STANDARD.encode(rand::random::<[u8; 16]>());
+    let mut req = format!(
+        "GET / HTTP/1.1\r\nHost: {}\r\nUser-Agent: curl/7.{}.{}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {}\r\nContent-Length: {}\r\n\r\n",
+        host,
+        rand::random_range(0..51),
+        rand::random_range(0..2),
+        key,
+        payload.len()
+    )
+    .into_bytes();
+    req.extend_from_slice(payload);
+    req
+}
+
+#[cfg(test)]
+fn http_response() -> Vec<u8> {
+    let accept = base64::engine::general_purpose::STANDARD.encode(rand::random::<[u8; 20]>());
+    format!(
+        "HTTP/1.1 101 Switching Protocols\r\nServer: nginx/1.{}.{}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        rand::random_range(0..11),
+        rand::random_range(0..12),
+        accept
+    )
+    .into_bytes()
+}
+
+/// Reads until the end of an HTTP header block; returns the header and any bytes after it.
+async fn read_http_head<R: AsyncRead + Unpin>(stream: &mut R) -> io::Result<(Vec<u8>, Vec<u8>)> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+    loop {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            let rest = buf.split_off(pos + 4);
+            return Ok((buf, rest));
+        }
+        if buf.len() > MAX_HEADER {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "OBFS_HTTP_HEADER_TOO_LARGE"));
+        }
+    }
+}
+
+// --- TLS FRAMING ---
+
+fn tls_record(content_type: u8, body: &[u8]) -> Vec<u8> {
+    let mut rec = Vec::with_capacity(body.len() + 5);
+    rec.push(content_type);
+    rec.extend_from_slice(&[0x03, 0x03]);
+    rec.extend_from_slice(&(body.len() as u16).to_be_bytes());
+    rec.extend_from_slice(body);
+    rec
+}
+
+fn tls_app_data(payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(payload.len() + 5);
+    for chunk in payload.chunks(MAX_RECORD) {
+        out.extend_from_slice(&tls_record(0x17, chunk));
+    }
+    out
+}
+
+fn push_ext(out: &mut Vec<u8>, ext_type: u16, data: &[u8]) {
+    out.extend_from_slice(&ext_type.to_be_bytes());
+    out.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    out.extend_from_slice(data);
+}
+
+/// ClientHello carrying `payload` in the session ticket extension, as simple-obfs does.
+pub(crate) fn tls_client_hello(host: &str, payload: &[u8]) -> Vec<u8> {
+    let mut ext = Vec::with_capacity(payload.len() + host.len() + 64);
+    let mut sni = Vec::with_capacity(host.len() + 5);
+    sni.extend_from_slice(&((host.len() + 3) as u16).to_be_bytes());
+    sni.push(0x00);
+    sni.extend_from_slice(&(host.len() as u16).to_be_bytes());
+    sni.extend_from_slice(host.as_bytes());
+    push_ext(&mut ext, 0x0000, &sni);
+    push_ext(&mut ext, 0x0023, payload);
+    push_ext(&mut ext, 0x000b, &[0x01, 0x00]);
+    push_ext(&mut ext, 0x000a, &[0x00, 0x04, 0x00, 0x1d, 0x00, 0x17]);
+    push_ext(&mut ext, 0x000d, &[0x00, 0x04, 0x04, 0x03, 0x08, 0x04]);
+
+    let mut body = Vec::with_capacity(ext.len() + 96);
+    body.extend_from_slice(&[0x03, 0x03]);
+    body.extend_from_slice(&rand::random::<[u8; 32]>());
+    body.push(32);
+    body.extend_from_slice(&rand::random::<[u8; 32]>());
+    let suites: [u8; 8] = [0xc0, 0x2b, 0xc0, 0x2f, 0xcc, 0xa9, 0xcc, 0xa8];
+    body.extend_from_slice(&(suites.len() as u16).to_be_bytes());
+    body.extend_from_slice(&suites);
+    body.extend_from_slice(&[0x01, 0x00]);
+    body.extend_from_slice(&(ext.len() as u16).to_be_bytes());
+    body.extend_from_slice(&ext);
+
+    tls_record(0x16, &handshake(0x01, &body))
+}
+
+#[cfg(test)]
+fn tls_server_hello(client_hello: &[u8]) -> Vec<u8> {
+    // Echo the client's session id like a resuming server would
+    let session_id = client_hello.get(38..).and_then(|rest| {
+        let len = *rest.first()? as usize;
+        rest.get(1..1 + len)
+    });
+    let mut body = Vec::with_capacity(96);
+    body.extend_from_slice(&[0x03, 0x03]);
+    body.extend_from_slice(&rand::random::<[u8; 32]>());
+    let session_id = session_id.unwrap_or(&[]);
+    body.push(session_id.len() as u8);
+    body.extend_from_slice(session_id);
+    body.extend_from_slice(&[0xcc, 0xa8, 0x00]);
+    let mut ext = Vec::new();
+    push_ext(&mut ext, 0xff01, &[0x00]);
+    push_ext(&mut ext, 0x0017, &[]);
+    body.extend_from_slice(&(ext.len() as u16).to_be_bytes());
+    body.extend_from_slice(&ext);
+
+    let mut out = tls_record(0x16, &handshake(0x02, &body));
+    out.extend_from_slice(&tls_record(0x14, &[0x01]));
+    out.extend_from_slice(&tls_record(0x16, &rand::random::<[u8; 40]>()));
+    out
+}
+
+fn tls_client_finish() -> Vec<u8> {
+    let mut out = tls_record(0x14, &[0x01]);
+    out.extend_from_slice(&tls_record(0x16, &rand::random::<[u8; 32]>()));
+    out
+}
+
+fn handshake(msg_type: u8, body: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(body.len() + 4);
+    out.push(msg_type);
+    out.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+    out.extend_from_slice(body);
+    out
+}
+
+/// Pulls the session ticket extension out of a ClientHello handshake message.
+#[cfg(test)]
+fn parse_session_ticket(hello: &[u8]) -> Option<Vec<u8>> {
+    if *hello.first()? != 0x01 {
+        return None;
+    }
+    // type(1) len(3) version(2) random(32)
+    let mut pos = 38;
+    let sid_len = *hello.get(pos)? as usize;
+    pos += 1 + sid_len;
+    let suites_len = u16::from_be_bytes([*hello.get(pos)?, *hello.get(pos + 1)?]) as usize;
+    pos += 2 + suites_len;
+    let comp_len = *hello.get(pos)? as usize;
+    pos += 1 + comp_len;
+    let ext_len = u16::from_be_bytes([*hello.get(pos)?, *hello.get(pos + 1)?]) as usize;
+    pos += 2;
+    let end = pos + ext_len;
+    while pos + 4 <= end {
+        let ext_type = u16::from_be_bytes([hello[pos], hello[pos + 1]]);
+        let len = u16::from_be_bytes([hello[pos + 2], hello[pos + 3]]) as usize;
+        let data = hello.get(pos + 4..pos + 4 + len)?;
+        if ext_type == 0x0023 {
+            return Some(data.to_vec());
+        }
+        pos += 4 + len;
+    }
+    None
+}
+
+pub(crate) async fn read_tls_record<R: AsyncRead + Unpin>(stream: &mut R) -> io::Result<(u8, Vec<u8>)> {
+    let mut header = [0u8; 5];
+    stream.read_exact(&mut header).await?;
+    let len = u16::from_be_bytes([header[3], header[4]]) as usize;
+    if header[1] != 0x03 || len > MAX_RECORD + 2048 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "OBFS_TLS_BAD_RECORD"));
+    }
+    let mut body = vec![0u8; len];
+    stream.read_exact(&mut body).await?;
+    Ok((header[0], body))
+}
+
+/// Forwards the payload of application data records, skipping handshake leftovers.
+async fn unwrap_tls_records<R, W>(mut from: R, to: &mut W) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    loop {
+        let (content_type, body) = match read_tls_record(&mut from).await {
+            Ok(rec) => rec,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return to.shutdown().await,
+            Err(e) => return Err(e),
+        };
+        match content_type {
+            0x17 => to.write_all(&body).await?,
+            0x14 | 0x16 => continue,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "OBFS_TLS_BAD_RECORD")),
+        }
+    }
+}
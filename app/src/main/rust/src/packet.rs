@@ -0,0 +1,77 @@
+use std::net::{IpAddr, SocketAddr};
+use std::ops::Range;
+
+// --- PACKET SUMMARY: PARSED ONCE PER FRAME ---
+// Routing, the kill switch and the reject path all need the same few header fields. The
+// filter parses each frame once and hands this summary around instead of the raw bytes.
+
+pub const TCP_FIN: u8 = 0x01;
+pub const TCP_SYN: u8 = 0x02;
+pub const TCP_RST: u8 = 0x04;
+pub const TCP_ACK: u8 = 0x10;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Transport {
+    Tcp { seq: u32, ack: u32, flags: u8 },
+    Udp,
+    // ICMP and anything else tun2proxy does not relay; ports are 0
+    Other,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PacketInfo {
+    pub src: SocketAddr,
+    pub dst: SocketAddr,
+    pub transport: Transport,
+    // Transport payload within the frame
+    pub payload: Range<usize>,
+}
+
+impl PacketInfo {
+    pub fn parse(frame: &[u8]) -> Option<Self> {
+        let sliced = etherparse::SlicedPacket::from_ip(frame).ok()?;
+        let (src_ip, dst_ip) = match &sliced.net {
+            Some(etherparse::NetSlice::Ipv4(v4)) => {
+                (IpAddr::V4(v4.header().source_addr()), IpAddr::V4(v4.header().destination_addr()))
+            }
+            Some(etherparse::NetSlice::Ipv6(v6)) => {
+                (IpAddr::V6(v6.header().source_addr()), IpAddr::V6(v6.header().destination_addr()))
+            }
+            None => return None,
+        };
+        let offset = |payload: &[u8]| {
+            let start = payload.as_ptr() as usize - frame.as_ptr() as usize;
+            start..start + payload.len()
+        };
+        let (src_port, dst_port, transport, payload) = match &sliced.transport {
+            Some(etherparse::TransportSlice::Tcp(tcp)) => {
+                let flags = (tcp.fin() as u8 * TCP_FIN)
+                    | (tcp.syn() as u8 * TCP_SYN)
+                    | (tcp.rst() as u8 * TCP_RST)
+                    | (tcp.ack() as u8 * TCP_ACK);
+                let transport = Transport::Tcp { seq: tcp.sequence_number(), ack: tcp.acknowledgment_number(), flags };
+                (tcp.source_port(), tcp.destination_port(), transport, offset(tcp.payload()))
+            }
+            Some(etherparse::TransportSlice::Udp(udp)) => {
+                (udp.source_port(), udp.destination_port(), Transport::Udp, offset(udp.payload()))
+            }
+            _ => (0, 0, Transport::Other, frame.len()..frame.len()),
+        };
+        Some(PacketInfo {
+            src: SocketAddr::new(src_ip, src_port),
+            dst: SocketAddr::new(dst_ip, dst_port),
+            transport,
+            payload,
+        })
+    }
+}
+
+/// Length of the IP packet at the start of `frame` according to its own header, if the
+/// frame carries IPv4 or IPv6 at all.
+pub fn ip_len(frame: &[u8]) -> Option<usize> {
+    match frame.first()? >> 4 {
+        4 => Some(u16::from_be_bytes([*frame.get(2)?, *frame.get(3)?]) as usize),
+        6 => Some(40 + u16::from_be_bytes([*frame.get(4)?, *frame.get(5)?]) as usize),
+        _ => None,
+    }
+}
@@ -0,0 +1,76 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::PathBuf;
+
+// --- SOCKET OWNERS FROM /proc/net ---
+// Lockdown finds the app behind a packet by its local port in the kernel's socket tables.
+// The tables come from a `ProcSource`, so tests can hand in synthetic ones. The kernel
+// rewrites them while we read, so a line is only trusted if it is complete.
+
+const UID_COLUMN: usize = 7;
+// `timeout` and `inode` follow the UID; a line without them was cut off mid-read
+const COLUMNS_AFTER_UID: usize = 2;
+
+pub trait ProcSource {
+    /// Opens one socket table: `tcp`, `tcp6`, `udp` or `udp6`.
+    fn open(&self, table: &str) -> io::Result<Box<dyn BufRead + '_>>;
+}
+
+/// Tables read from a directory laid out like `/proc/net`.
+pub struct ProcFs {
+    root: PathBuf,
+}
+
+impl ProcFs {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        ProcFs { root: root.into() }
+    }
+
+    pub fn system() -> Self {
+        ProcFs::new("/proc/net")
+    }
+}
+
+impl ProcSource for ProcFs {
+    fn open(&self, table: &str) -> io::Result<Box<dyn BufRead + '_>> {
+        Ok(Box::new(BufReader::new(File::open(self.root.join(table))?)))
+    }
+}
+
+/// UID owning the local `port`, looked up in the live tables.
+pub fn find_uid_by_port(port: u16, is_udp: bool) -> Option<u32> {
+    find_uid(&ProcFs::system(), port, is_udp)
+}
+
+pub fn find_uid(source: &dyn ProcSource, port: u16, is_udp: bool) -> Option<u32> {
+    // Dual-stack sockets (most Java sockets) are listed in the v6 tables even for IPv4 flows
+    let tables: [&str; 2] = if is_udp { ["udp", "udp6"] } else { ["tcp", "tcp6"] };
+    for table in tables {
+        let Ok(reader) = source.open(table) else { continue };
+        // Skip header
+        for line in reader.lines().skip(1) {
+            let Ok(line) = line else { break };
+            if let Some((p, uid)) = parse_line(&line) {
+                if p == port {
+                    return Some(uid);
+                }
+            }
+        }
+    }
+    None
+}
+
+// `  0: 0100007F:2710 00000000:0000 0A ... 10123 0 45678 ...` -> (port, uid)
+fn parse_line(line: &str) -> Option<(u16, u32)> {
+    let mut columns = line.split_whitespace();
+    let (ip, port) = columns.nth(1)?.split_once(':')?;
+    let hex = |s: &str| s.bytes().all(|b| b.is_ascii_hexdigit());
+    if !matches!(ip.len(), 8 | 32) || port.len() != 4 || !hex(ip) || !hex(port) {
+        return None;
+    }
+    let uid = columns.nth(UID_COLUMN - 2)?;
+    if columns.take(COLUMNS_AFTER_UID).count() < COLUMNS_AFTER_UID {
+        return None;
+    }
+    Some((u16::from_str_radix(port, 16).ok()?, uid.parse().ok()?))
+}
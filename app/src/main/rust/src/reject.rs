@@ -0,0 +1,58 @@
+use etherparse::PacketBuilder;
+use std::net::IpAddr;
+use crate::packet::{PacketInfo, Transport, TCP_ACK, TCP_FIN, TCP_RST, TCP_SYN};
+
+// --- FAST REJECT: RST / ICMP UNREACHABLE FOR BLOCKED FLOWS ---
+// A silently dropped SYN keeps the app retrying (and the radio awake) for tens of seconds.
+// Answering with a reset or an unreachable fails the connect immediately instead.
+
+const TTL: u8 = 64;
+// RFC 1812: quote the IP header plus at least 8 bytes; stay well inside the minimum MTU
+const ICMPV4_QUOTE: usize = 548;
+const ICMPV6_QUOTE: usize = 1232;
+
+/// Builds the reply that rejects `packet`: a TCP RST per RFC 793 for TCP, "administratively
+/// prohibited" ICMP/ICMPv6 for UDP. Never answers resets or ICMP, so two filters cannot
+/// ping-pong.
+pub fn reject_packet(packet: &[u8]) -> Option<Vec<u8>> {
+    reject_for(&PacketInfo::parse(packet)?, packet)
+}
+
+/// Same as `reject_packet` for a frame that has already been parsed.
+pub fn reject_for(info: &PacketInfo, frame: &[u8]) -> Option<Vec<u8>> {
+    let builder = match (info.dst.ip(), info.src.ip()) {
+        (IpAddr::V4(from), IpAddr::V4(to)) => PacketBuilder::ipv4(from.octets(), to.octets(), TTL),
+        (IpAddr::V6(from), IpAddr::V6(to)) => PacketBuilder::ipv6(from.octets(), to.octets(), TTL),
+        _ => return None,
+    };
+    let (reply_src, reply_dst) = (info.dst.port(), info.src.port());
+
+    let mut out = Vec::new();
+    match info.transport {
+        Transport::Tcp { seq, ack, flags } => {
+            if flags & TCP_RST != 0 {
+                return None;
+            }
+            let builder = if flags & TCP_ACK != 0 {
+                // <SEQ=SEG.ACK><CTL=RST>
+                builder.tcp(reply_src, reply_dst, ack, 0).rst()
+            } else {
+                // <SEQ=0><ACK=SEG.SEQ+SEG.LEN><CTL=RST,ACK>
+                let seg_len = info.payload.len() as u32 + (flags & TCP_SYN != 0) as u32 + (flags & TCP_FIN != 0) as u32;
+                builder.tcp(reply_src, reply_dst, 0, 0).rst().ack(seq.wrapping_add(seg_len))
+            };
+            builder.write(&mut out, &[]).ok()?;
+        }
+        Transport::Udp => {
+            if info.src.is_ipv4() {
+                let quote = &frame[..frame.len().min(ICMPV4_QUOTE)];
+                builder.icmpv4_raw(3, 13, [0; 4]).write(&mut out, quote).ok()?;
+            } else {
+                let quote = &frame[..frame.len().min(ICMPV6_QUOTE)];
+                builder.icmpv6_raw(1, 1, [0; 4]).write(&mut out, quote).ok()?;
+            }
+        }
+        Transport::Other => return None,
+    }
+    Some(out)
+}
@@ -0,0 +1,494 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use crate::cidr::{CidrTable, LAN_RANGES};
+use crate::common::*;
+use crate::geoip::GeoIpDb;
+use crate::packet::{PacketInfo, Transport};
+
+// --- ROUTING POLICY: PROXY / DIRECT / BLOCK PER FLOW ---
+// Decided once per flow inside the TUN pipeline, so changing the policy never needs a new
+// VpnService. Direct flows are handed to a second, proxy-less tun2proxy stack.
+
+// Most flows the table holds; past it the least recently seen make room
+const FLOW_TABLE_CAP: usize = 4096;
+const FLOW_IDLE: Duration = Duration::from_secs(120);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Proxy,
+    Direct,
+    Block,
+}
+
+/// What a blocked flow sees: nothing at all, or an immediate RST / ICMP unreachable.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlockMode {
+    Drop,
+    #[default]
+    Reject,
+}
+
+/// What happens to inbound packets that answer no flow an app opened.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Unsolicited {
+    #[default]
+    Allow,
+    Drop,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PolicyDocument {
+    #[serde(default = "default_action")]
+    default: Action,
+    #[serde(default)]
+    block_mode: BlockMode,
+    #[serde(default)]
+    unsolicited: Unsolicited,
+    #[serde(default)]
+    rules: Vec<RuleSpec>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleSpec {
+    uid: Option<u32>,
+    domain: Option<String>,
+    cidr: Option<String>,
+    country: Option<String>,
+    action: Action,
+}
+
+fn default_action() -> Action {
+    Action::Proxy
+}
+
+#[derive(Debug)]
+pub struct RoutingPolicy {
+    pub default: Action,
+    pub block_mode: BlockMode,
+    pub unsolicited: Unsolicited,
+    uid_rules: HashMap<u32, Action>,
+    domain_rules: Vec<(String, Action)>,
+    cidr_rules: CidrTable<Action>,
+    country_rules: HashMap<String, Action>,
+    lan: CidrTable<()>,
+}
+
+impl Default for RoutingPolicy {
+    fn default() -> Self {
+        let mut lan = CidrTable::default();
+        for range in LAN_RANGES {
+            let _ = lan.insert_str(range, ());
+        }
+        RoutingPolicy {
+            default: Action::Proxy,
+            block_mode: BlockMode::default(),
+            unsolicited: Unsolicited::default(),
+            uid_rules: HashMap::new(),
+            domain_rules: Vec::new(),
+            cidr_rules: CidrTable::default(),
+            country_rules: HashMap::new(),
+            lan,
+        }
+    }
+}
+
+impl RoutingPolicy {
+    /// Parses `{"default":"proxy","block_mode":"reject","rules":[{"uid":10123,"action":"direct"},{"domain":"ads.example","action":"block"}]}`.
+    /// Domain rules are matched against the TLS SNI, which only shows up after the flow has
+    /// been routed, so they may only block. Destination rules take `{"cidr":"203.0.113.0/24"}`
+    /// or `{"country":"MM"}`; the latter needs a GeoIP database (see `setGeoIpDatabase`).
+    /// `"unsolicited":"drop"` discards inbound packets that answer no outbound flow.
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        let doc: PolicyDocument = serde_json::from_str(json).map_err(|e| format!("INVALID_POLICY: {}", e))?;
+        let mut policy = RoutingPolicy {
+            default: doc.default,
+            block_mode: doc.block_mode,
+            unsolicited: doc.unsolicited,
+            ..Default::default()
+        };
+
+        for (i, rule) in doc.rules.into_iter().enumerate() {
+            let matchers = [rule.uid.is_some(), rule.domain.is_some(), rule.cidr.is_some(), rule.country.is_some()];
+            match matchers.iter().filter(|m| **m).count() {
+                0 => return Err(format!("RULE_{}: MISSING_MATCHER", i)),
+                1 => {}
+                _ => return Err(format!("RULE_{}: AMBIGUOUS_MATCHER", i)),
+            }
+
+            if let Some(uid) = rule.uid {
+                if policy.uid_rules.insert(uid, rule.action).is_some() {
+                    return Err(format!("RULE_{}: DUPLICATE_UID_{}", i, uid));
+                }
+            } else if let Some(domain) = rule.domain {
+                let domain = domain.trim().trim_start_matches("*.").trim_end_matches('.').to_ascii_lowercase();
+                if domain.is_empty() {
+                    return Err(format!("RULE_{}: EMPTY_DOMAIN", i));
+                }
+                if rule.action != Action::Block {
+                    return Err(format!("RULE_{}: DOMAIN_RULES_ONLY_BLOCK", i));
+                }
+                policy.domain_rules.push((domain, rule.action));
+            } else if let Some(cidr) = rule.cidr {
+                policy.cidr_rules.insert_str(&cidr, rule.action).map_err(|e| format!("RULE_{}: {}", i, e))?;
+            } else if let Some(country) = rule.country {
+                let country = country.trim().to_ascii_uppercase();
+                if country.len() != 2 || !country.bytes().all(|b| b.is_ascii_uppercase()) {
+                    return Err(format!("RULE_{}: INVALID_COUNTRY: {}", i, country));
+                }
+                if policy.country_rules.insert(country.clone(), rule.action).is_some() {
+                    return Err(format!("RULE_{}: DUPLICATE_COUNTRY_{}", i, country));
+                }
+            }
+        }
+        Ok(policy)
+    }
+
+    pub fn action_for_uid(&self, uid: u32) -> Option<Action> {
+        self.uid_rules.get(&uid).copied()
+    }
+
+    /// Suffix match on label boundaries: `example.com` covers `cdn.example.com`.
+    pub fn action_for_domain(&self, host: &str) -> Option<Action> {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        self.domain_rules.iter().find_map(|(domain, action)| {
+            let hit = host == *domain
+                || (host.len() > domain.len()
+                    && host.ends_with(domain.as_str())
+                    && host.as_bytes()[host.len() - domain.len() - 1] == b'.');
+            hit.then_some(*action)
+        })
+    }
+
+    pub fn has_domain_rules(&self) -> bool {
+        !self.domain_rules.is_empty()
+    }
+
+    pub fn has_country_rules(&self) -> bool {
+        !self.country_rules.is_empty()
+    }
+
+    /// Most specific CIDR rule wins; country rules only apply where no CIDR matched.
+    pub fn action_for_ip(&self, ip: IpAddr, geoip: Option<&GeoIpDb>) -> Option<Action> {
+        if let Some(action) = self.cidr_rules.lookup(ip) {
+            return Some(action);
+        }
+        if self.country_rules.is_empty() {
+            return None;
+        }
+        let country = geoip?.country(ip)?;
+        self.country_rules.get(&country).copied()
+    }
+
+    pub fn is_lan(&self, ip: IpAddr) -> bool {
+        self.lan.lookup(ip).is_some()
+    }
+}
+
+/// Block mode of the installed policy, for paths that do not keep a FlowTable.
+pub fn current_block_mode() -> BlockMode {
+    ROUTING_POLICY.read().map(|p| p.block_mode).unwrap_or_default()
+}
+
+/// Loads (or with an empty path, unloads) the GeoIP database used by country rules.
+pub fn install_geoip(path: &str) -> Result<(), String> {
+    let db = if path.is_empty() { None } else { Some(Arc::new(GeoIpDb::open(path)?)) };
+    if let Ok(mut current) = GEOIP_DB.write() {
+        *current = db;
+    }
+    POLICY_GENERATION.fetch_add(1, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Swaps in a new policy; flows re-evaluate on their next packet.
+pub fn install_policy(policy: RoutingPolicy) {
+    if let Ok(mut current) = ROUTING_POLICY.write() {
+        *current = Arc::new(policy);
+    }
+    POLICY_GENERATION.fetch_add(1, Ordering::SeqCst);
+}
+
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub struct FlowKey {
+    pub udp: bool,
+    pub src: SocketAddr,
+    pub dst: SocketAddr,
+}
+
+struct FlowEntry {
+    action: Action,
+    // Owning app, kept so inbound packets and re-evaluations need no /proc lookup
+    uid: Option<u32>,
+    generation: u64,
+    last_seen: Instant,
+    sni_checked: bool,
+}
+
+/// Per-session flow verdicts. UID lookups hit /proc, so they happen once per flow only.
+pub struct FlowTable {
+    flows: HashMap<FlowKey, FlowEntry>,
+    policy: Arc<RoutingPolicy>,
+    geoip: Option<Arc<GeoIpDb>>,
+    allowed_uids: Vec<u32>,
+    // Fake addresses from tun2proxy's virtual DNS; only the proxy stack can map them back
+    virtual_dns: CidrTable<()>,
+    // The interface's own subnets; they overlap LAN_RANGES but are not the LAN
+    tun_subnets: CidrTable<()>,
+    generation: u64,
+    // Last sweep for idle flows
+    pruned: Instant,
+}
+
+impl Default for FlowTable {
+    fn default() -> Self {
+        FlowTable {
+            flows: HashMap::new(),
+            policy: Arc::default(),
+            geoip: None,
+            allowed_uids: Vec::new(),
+            virtual_dns: CidrTable::default(),
+            tun_subnets: CidrTable::default(),
+            generation: u64::MAX,
+            pruned: Instant::now(),
+        }
+    }
+}
+
+impl FlowTable {
+    pub fn block_mode(&self) -> BlockMode {
+        self.policy.block_mode
+    }
+
+    pub fn unsolicited(&self) -> Unsolicited {
+        self.policy.unsolicited
+    }
+
+    /// Marks the range tun2proxy's virtual DNS answers from.
+    pub fn set_virtual_dns_pool(&mut self, (ip, len): (IpAddr, u8)) {
+        let mut table = CidrTable::default();
+        table.insert(ip, len, ());
+        self.virtual_dns = table;
+    }
+
+    /// Marks the TUN interface's own subnets, which LAN bypass must not claim.
+    pub fn set_tun_subnets(&mut self, subnets: &[(IpAddr, u8)]) {
+        let mut table = CidrTable::default();
+        for &(ip, len) in subnets {
+            table.insert(ip, len, ());
+        }
+        self.tun_subnets = table;
+    }
+
+    fn refresh(&mut self) {
+        let generation = POLICY_GENERATION.load(Ordering::SeqCst);
+        if generation == self.generation {
+            return;
+        }
+        self.generation = generation;
+        if let Ok(policy) = ROUTING_POLICY.read() {
+            self.policy = policy.clone();
+        }
+        if let Ok(geoip) = GEOIP_DB.read() {
+            self.geoip = geoip.clone();
+        }
+        if let Ok(allowed) = ALLOWED_UIDS.read() {
+            self.allowed_uids = allowed.clone();
+        }
+    }
+
+    // A flood of live flows: drop the least recently seen down to 7/8 of the cap, so the
+    // scan is paid once per batch rather than on every new flow. An evicted flow that is
+    // still alive is simply classified again on its next packet.
+    fn evict_oldest(&mut self) {
+        let excess = self.flows.len() + 1 - FLOW_TABLE_CAP * 7 / 8;
+        let mut ages: Vec<(Instant, FlowKey)> = self.flows.iter().map(|(k, e)| (e.last_seen, *k)).collect();
+        ages.select_nth_unstable_by_key(excess - 1, |(seen, _)| *seen);
+        for (_, key) in &ages[..excess] {
+            self.flows.remove(key);
+        }
+    }
+
+    #[cfg(test)]
+    pub fn flow_count(&self) -> usize {
+        self.flows.len()
+    }
+
+    /// Who may talk at all is settled first (UID block rules, then the FOCUS allowlist).
+    /// LAN destinations outside the TUN's own subnets then always go direct; after that UID rules beat destination
+    /// (CIDR, then country) rules, and the default covers the rest.
+    fn evaluate(&self, uid: Option<u32>, dst: IpAddr) -> Action {
+        let uid_rule = uid.and_then(|u| self.policy.action_for_uid(u));
+        match (uid_rule, uid) {
+            (Some(Action::Block), _) => return Action::Block,
+            (None, Some(uid)) if self.locked_out(uid) => {
+                return Action::Block;
+            }
+            _ => {}
+        }
+        if self.policy.is_lan(dst) && self.tun_subnets.lookup(dst).is_none() {
+            return Action::Direct;
+        }
+        if self.virtual_dns.lookup(dst).is_some() {
+            // Not a real address, so destination rules say nothing and Direct cannot reach it
+            return match uid_rule.unwrap_or(self.policy.default) {
+                Action::Block => Action::Block,
+                _ => Action::Proxy,
+            };
+        }
+        if let Some(action) = uid_rule {
+            return action;
+        }
+        self.policy
+            .action_for_ip(dst, self.geoip.as_deref())
+            .unwrap_or(self.policy.default)
+    }
+
+    // Off the FOCUS allowlist, which blocks the app whatever the other rules say
+    fn locked_out(&self, uid: u32) -> bool {
+        !self.allowed_uids.is_empty() && !self.allowed_uids.contains(&uid)
+    }
+
+    fn reevaluate(&self, uid: Option<u32>, dst: IpAddr, old: Option<Action>) -> Action {
+        let action = self.evaluate(uid, dst);
+        match old {
+            // An established flow cannot move between stacks; a new policy may only cut it
+            Some(old) if action != Action::Block && old != Action::Block => old,
+            _ => action,
+        }
+    }
+
+    /// Verdict and owner for a packet on its way back to the apps. Replies inherit the
+    /// verdict of the outbound flow they answer; `None` means no app opened that flow.
+    pub fn classify_inbound(&mut self, info: &PacketInfo) -> Option<(Action, Option<u32>)> {
+        self.refresh();
+        let key = FlowKey { udp: info.transport == Transport::Udp, src: info.dst, dst: info.src };
+        let generation = self.generation;
+        let (uid, action) = {
+            let entry = self.flows.get(&key)?;
+            if entry.generation == generation {
+                (entry.uid, entry.action)
+            } else {
+                (entry.uid, self.reevaluate(entry.uid, key.dst.ip(), Some(entry.action)))
+            }
+        };
+        let entry = self.flows.get_mut(&key)?;
+        entry.action = action;
+        entry.generation = generation;
+        entry.last_seen = Instant::now();
+        Some((action, uid))
+    }
+
+    /// Returns the verdict for an outbound packet. `resolve_uid(port, is_udp)` maps the
+    /// local port to the owning app.
+    #[cfg(test)]
+    pub fn classify<F>(&mut self, packet: &[u8], resolve_uid: F) -> Action
+    where
+        F: FnOnce(u16, bool) -> Option<u32>,
+    {
+        let info = PacketInfo::parse(packet);
+        self.classify_frame(packet, info.as_ref(), resolve_uid)
+    }
+
+    /// Owner of the outbound flow `info` belongs to, as resolved when the flow was first seen.
+    pub fn uid_of(&self, info: &PacketInfo) -> Option<u32> {
+        let key = FlowKey { udp: info.transport == Transport::Udp, src: info.src, dst: info.dst };
+        self.flows.get(&key).and_then(|e| e.uid)
+    }
+
+    /// Verdict for a frame the caller has already parsed; `info` is `None` for frames that
+    /// are not TCP/UDP over IP.
+    pub fn classify_frame<F>(&mut self, frame: &[u8], info: Option<&PacketInfo>, resolve_uid: F) -> Action
+    where
+        F: FnOnce(u16, bool) -> Option<u32>,
+    {
+        self.refresh();
+        let info = match info {
+            Some(info) if info.transport != Transport::Other => info,
+            // ICMP and friends: tun2proxy has no use for them, keep the default path
+            _ => return self.policy.default,
+        };
+        let key = FlowKey { udp: info.transport == Transport::Udp, src: info.src, dst: info.dst };
+        let payload = frame.get(info.payload.clone()).unwrap_or_default();
+
+        let now = Instant::now();
+        let generation = self.generation;
+        let stale = match self.flows.get(&key) {
+            Some(entry) => entry.generation != generation,
+            None => true,
+        };
+        if stale {
+            let uid = resolve_uid(key.src.port(), key.udp);
+            let old = self.flows.get(&key).map(|e| e.action);
+            let action = self.reevaluate(uid, key.dst.ip(), old);
+            if old.is_none() && action == Action::Block {
+                BLOCKED_FLOWS.fetch_add(1, Ordering::Relaxed);
+                if uid.is_some_and(|u| self.locked_out(u)) {
+                    LOCKDOWN_BLOCKED_FLOWS.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            if self.flows.len() >= FLOW_TABLE_CAP || now.duration_since(self.pruned) >= FLOW_IDLE {
+                self.flows.retain(|_, e| now.duration_since(e.last_seen) < FLOW_IDLE);
+                self.pruned = now;
+            }
+            if self.flows.len() >= FLOW_TABLE_CAP {
+                self.evict_oldest();
+            }
+            self.flows.insert(key, FlowEntry { action, uid, generation, last_seen: now, sni_checked: false });
+            ACTIVE_FLOWS.store(self.flows.len() as u64, Ordering::Relaxed);
+        }
+
+        let check_sni = self.policy.has_domain_rules() && !key.udp && !payload.is_empty();
+        let policy = self.policy.clone();
+        let entry = match self.flows.get_mut(&key) {
+            Some(e) => e,
+            None => return self.policy.default,
+        };
+        entry.last_seen = now;
+        if check_sni && !entry.sni_checked && entry.action != Action::Block {
+            entry.sni_checked = true;
+            if let Some(action) = extract_sni(payload).and_then(|host| policy.action_for_domain(host)) {
+                entry.action = action;
+            }
+        }
+        entry.action
+    }
+}
+
+/// Server name from a TLS ClientHello at the start of `payload`, if there is one.
+pub fn extract_sni(payload: &[u8]) -> Option<&str> {
+    // record header(5) + handshake header(4) + version(2) + random(32)
+    if payload.len() < 43 || payload[0] != 0x16 || payload[5] != 0x01 {
+        return None;
+    }
+    let mut pos = 43;
+    let sid_len = *payload.get(pos)? as usize;
+    pos += 1 + sid_len;
+    let suites_len = u16::from_be_bytes([*payload.get(pos)?, *payload.get(pos + 1)?]) as usize;
+    pos += 2 + suites_len;
+    let comp_len = *payload.get(pos)? as usize;
+    pos += 1 + comp_len;
+    let ext_total = u16::from_be_bytes([*payload.get(pos)?, *payload.get(pos + 1)?]) as usize;
+    pos += 2;
+    let end = (pos + ext_total).min(payload.len());
+    while pos + 4 <= end {
+        let ext_type = u16::from_be_bytes([payload[pos], payload[pos + 1]]);
+        let ext_len = u16::from_be_bytes([payload[pos + 2], payload[pos + 3]]) as usize;
+        pos += 4;
+        if ext_type == 0x0000 {
+            // server_name_list(2) + name_type(1) + name_len(2) + name
+            let name_len = u16::from_be_bytes([*payload.get(pos + 3)?, *payload.get(pos + 4)?]) as usize;
+            let name = payload.get(pos + 5..pos + 5 + name_len)?;
+            return std::str::from_utf8(name).ok();
+        }
+        pos += ext_len;
+    }
+    None
+}
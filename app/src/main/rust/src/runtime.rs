@@ -0,0 +1,164 @@
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::runtime::{Builder, Runtime};
+use crate::common::*;
+
+// --- ENGINE RUNTIME: ONE TOKIO RUNTIME PER SESSION ---
+// Every runVpnLoop / runPassiveShield call builds its own runtime from the stored config and
+// shuts it down when the session returns, so nothing spawned by ss-local, tun2proxy or the
+// monitors outlives the VPN. Low-end phones can trade throughput for battery by running
+// everything on the JNI thread.
+
+// Linux cuts thread names at 15 bytes
+const MAX_THREAD_NAME: usize = 15;
+// How long shutdown waits for blocking tasks (DNS lookups, /proc reads) before leaving them
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(2);
+
+static SESSION_IDS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Flavor {
+    #[default]
+    MultiThread,
+    // Everything runs on the thread that started the session
+    CurrentThread,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RuntimeConfig {
+    pub flavor: Flavor,
+    pub worker_threads: usize,
+    pub max_blocking_threads: usize,
+    pub thread_name: String,
+}
+
+impl Default for RuntimeConfig {
+    fn default() -> Self {
+        RuntimeConfig {
+            flavor: Flavor::MultiThread,
+            worker_threads: 2,
+            max_blocking_threads: 512,
+            thread_name: "igy-core".to_string(),
+        }
+    }
+}
+
+impl RuntimeConfig {
+    /// Parses `{"flavor":"current_thread","worker_threads":2,"max_blocking_threads":8,"thread_name":"igy-core"}`;
+    /// missing fields keep their defaults.
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        let config: RuntimeConfig = serde_json::from_str(json).map_err(|e| format!("INVALID_RUNTIME_CONFIG: {}", e))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if !(1..=16).contains(&self.worker_threads) {
+            return Err(format!("WORKER_THREADS_OUT_OF_RANGE: {}", self.worker_threads));
+        }
+        if !(1..=512).contains(&self.max_blocking_threads) {
+            return Err(format!("MAX_BLOCKING_THREADS_OUT_OF_RANGE: {}", self.max_blocking_threads));
+        }
+        let name = &self.thread_name;
+        if name.is_empty() || name.len() > MAX_THREAD_NAME || name.contains('\0') {
+            return Err(format!("INVALID_THREAD_NAME: {}", name));
+        }
+        Ok(())
+    }
+
+    /// Tasks that can truly run at once on this runtime.
+    pub fn parallelism(&self) -> usize {
+        match self.flavor {
+            Flavor::MultiThread => self.worker_threads,
+            Flavor::CurrentThread => 1,
+        }
+    }
+
+    /// Classifiers the passive shield may run. Each holds a blocking thread for the whole
+    /// session, so one thread of the pool is left for DNS lookups and file reads.
+    pub fn shield_workers(&self) -> usize {
+        self.parallelism().min(4).min(self.max_blocking_threads.saturating_sub(1)).max(1)
+    }
+
+    fn build(&self) -> std::io::Result<Runtime> {
+        let mut builder = match self.flavor {
+            Flavor::MultiThread => {
+                let mut b = Builder::new_multi_thread();
+                b.worker_threads(self.worker_threads);
+                b
+            }
+            Flavor::CurrentThread => Builder::new_current_thread(),
+        };
+        builder
+            .max_blocking_threads(self.max_blocking_threads)
+            .thread_name(self.thread_name.clone())
+            .enable_all()
+            .build()
+    }
+}
+
+/// Config the next session will be built with.
+pub fn current() -> RuntimeConfig {
+    RUNTIME_CONFIG.read().map(|c| c.clone()).unwrap_or_default()
+}
+
+pub fn configure(config: RuntimeConfig) {
+    if let Ok(mut current) = RUNTIME_CONFIG.write() {
+        *current = config;
+    }
+}
+
+/// Runs `session` on a fresh runtime and tears the runtime down afterwards. While it runs,
+/// `spawn` puts work on the same runtime. `None` if the runtime could not be built.
+pub fn run_session<F: Future>(session: F) -> Option<F::Output> {
+    let config = current();
+    let rt = match config.build() {
+        Ok(rt) => rt,
+        Err(e) => {
+            crate::log_to_java(&format!("VPN >> RUNTIME_BUILD_FAILED: {}", e));
+            CORE_STATUS.store(3, Ordering::SeqCst);
+            return None;
+        }
+    };
+    let id = SESSION_IDS.fetch_add(1, Ordering::SeqCst);
+    if let Ok(mut active) = SESSION_RUNTIME.write() {
+        *active = Some((id, rt.handle().clone()));
+    }
+
+    let output = rt.block_on(session);
+
+    if let Ok(mut active) = SESSION_RUNTIME.write() {
+        // A newer session may already have taken over
+        if active.as_ref().is_some_and(|(active_id, _)| *active_id == id) {
+            *active = None;
+        }
+    }
+    rt.shutdown_timeout(SHUTDOWN_GRACE);
+    Some(output)
+}
+
+/// Spawns `task` on the running session's runtime, or on a short-lived one of its own when
+/// no session is up.
+pub fn spawn<F>(task: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let handle = SESSION_RUNTIME.read().ok().and_then(|active| active.as_ref().map(|(_, h)| h.clone()));
+    match handle {
+        Some(handle) => {
+            handle.spawn(task);
+        }
+        None => {
+            let name = current().thread_name;
+            let _ = std::thread::Builder::new().name(name).spawn(move || {
+                if let Ok(rt) = Builder::new_current_thread().enable_all().build() {
+                    rt.block_on(task);
+                }
+            });
+        }
+    }
+}
@@ -0,0 +1,285 @@
+use std::io;
+use std::ops::Range;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::sync::Mutex;
+use tokio::io::unix::AsyncFd;
+use tokio::sync::mpsc;
+use tun2proxy::CancellationToken;
+use crate::capture::{self, Direction};
+use crate::common::*;
+use crate::packet::{PacketInfo, Transport};
+use crate::procnet::{find_uid, ProcFs, ProcSource};
+
+// --- PASSIVE SHIELD: PIPELINED PACKET CLASSIFICATION ---
+// Three stages joined by bounded channels: one reader drains the TUN in batches, a pool of
+// classifiers judges the packets, and a single stats stage publishes the counters and
+// hands the batch buffers back. A full channel stalls the stage before it, so a slow
+// classifier ends up leaving packets in the kernel queue instead of growing our memory.
+// Lockdown reads /proc for every packet, so the classifiers run on the blocking pool
+// rather than on the runtime's workers.
+
+// Largest frame the passive shield reads, as before
+const MAX_FRAME: usize = 16384;
+const BATCH_BYTES: usize = 256 * 1024;
+// Batches in flight between two stages
+const QUEUE_DEPTH: usize = 8;
+
+#[derive(Clone, Copy, Debug)]
+pub struct ShieldConfig {
+    // Classification threads; each takes whole batches
+    pub workers: usize,
+    // Frames read per batch before it is passed on
+    pub batch_frames: usize,
+    pub counters: ShieldCounters,
+}
+
+impl Default for ShieldConfig {
+    fn default() -> Self {
+        let cores = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(2);
+        ShieldConfig { workers: cores.clamp(1, 4), batch_frames: 64, counters: ShieldCounters::default() }
+    }
+}
+
+/// Protocol and byte counters the stats stage publishes to: the engine's own by default.
+#[derive(Clone, Copy, Debug)]
+pub struct ShieldCounters {
+    pub tcp: &'static AtomicU64,
+    pub udp: &'static AtomicU64,
+    pub other: &'static AtomicU64,
+    pub bytes: &'static AtomicU64,
+}
+
+impl Default for ShieldCounters {
+    fn default() -> Self {
+        ShieldCounters { tcp: &TCP_COUNT, udp: &UDP_COUNT, other: &OTHER_COUNT, bytes: &BYTES_PROCESSED }
+    }
+}
+
+struct Batch {
+    data: Vec<u8>,
+    frames: Vec<Range<usize>>,
+}
+
+impl Batch {
+    fn new() -> Self {
+        Batch { data: Vec::with_capacity(BATCH_BYTES), frames: Vec::new() }
+    }
+
+    fn room(&self) -> bool {
+        self.data.capacity() - self.data.len() >= MAX_FRAME
+    }
+}
+
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub struct ShieldTally {
+    pub packets: u64,
+    pub tcp: u64,
+    pub udp: u64,
+    pub allowed_bytes: u64,
+    pub dropped: u64,
+    // Packets whose app lockdown looked up, by outcome
+    pub lockdown_allowed: u64,
+    pub lockdown_dropped: u64,
+}
+
+impl ShieldTally {
+    fn add(&mut self, other: &ShieldTally) {
+        self.packets += other.packets;
+        self.tcp += other.tcp;
+        self.udp += other.udp;
+        self.allowed_bytes += other.allowed_bytes;
+        self.dropped += other.dropped;
+        self.lockdown_allowed += other.lockdown_allowed;
+        self.lockdown_dropped += other.lockdown_dropped;
+    }
+}
+
+/// Runs the pipeline on `fd` (already non-blocking) until `stop` fires, the fd hits EOF or a
+/// read fails. Only setting up the fd is an error; returns what it saw.
+pub async fn run(fd: RawFd, config: ShieldConfig, stop: &CancellationToken) -> io::Result<ShieldTally> {
+    let async_fd = AsyncFd::new(fd)?;
+    let (batch_tx, batch_rx) = mpsc::channel::<Batch>(QUEUE_DEPTH);
+    let (judged_tx, mut judged_rx) = mpsc::channel::<(Batch, ShieldTally)>(QUEUE_DEPTH);
+    let (recycle_tx, mut recycle_rx) = mpsc::channel::<Batch>(QUEUE_DEPTH * 2);
+
+    // Workers share one queue, so whichever is idle picks up the next batch
+    let batch_rx = Arc::new(Mutex::new(batch_rx));
+    for _ in 0..config.workers.max(1) {
+        let batch_rx = batch_rx.clone();
+        let judged_tx = judged_tx.clone();
+        tokio::task::spawn_blocking(move || loop {
+            let next = match batch_rx.lock() {
+                Ok(mut rx) => rx.blocking_recv(),
+                Err(_) => None,
+            };
+            let Some(batch) = next else { break };
+            let tally = classify_batch(&batch);
+            if judged_tx.blocking_send((batch, tally)).is_err() {
+                break;
+            }
+        });
+    }
+    drop(judged_tx);
+
+    let counters = config.counters;
+    let stats = tokio::spawn(async move {
+        let mut total = ShieldTally::default();
+        while let Some((mut batch, tally)) = judged_rx.recv().await {
+            counters.tcp.fetch_add(tally.tcp, Ordering::Relaxed);
+            counters.udp.fetch_add(tally.udp, Ordering::Relaxed);
+            counters.other.fetch_add(tally.packets - tally.tcp - tally.udp, Ordering::Relaxed);
+            counters.bytes.fetch_add(tally.allowed_bytes, Ordering::Relaxed);
+            LOCKDOWN_ALLOWED.fetch_add(tally.lockdown_allowed, Ordering::Relaxed);
+            LOCKDOWN_DROPPED.fetch_add(tally.lockdown_dropped, Ordering::Relaxed);
+            if tally.dropped > 0 {
+                crate::log_to_java(&format!("SHIELD >> TRAFFIC_DROPPED: UNAUTHORIZED_UID x{}", tally.dropped));
+            }
+            total.add(&tally);
+            batch.data.clear();
+            batch.frames.clear();
+            let _ = recycle_tx.try_send(batch);
+        }
+        total
+    });
+
+    let batch_frames = config.batch_frames.max(1);
+    loop {
+        // An idle TUN may never become readable again, so waiting must not outlast `stop`
+        let readable = tokio::select! {
+            readable = async_fd.readable() => readable,
+            _ = stop.cancelled() => break,
+        };
+        let Ok(mut guard) = readable else { break };
+        let mut batch = recycle_rx.try_recv().unwrap_or_else(|_| Batch::new());
+        let mut eof = false;
+        while batch.frames.len() < batch_frames && batch.room() {
+            let start = batch.data.len();
+            let n = unsafe {
+                libc::read(fd, batch.data.as_mut_ptr().add(start) as *mut libc::c_void, MAX_FRAME)
+            };
+            if n > 0 {
+                unsafe { batch.data.set_len(start + n as usize) };
+                batch.frames.push(start..start + n as usize);
+            } else if n == 0 {
+                eof = true;
+                break;
+            } else {
+                if io::Error::last_os_error().kind() == io::ErrorKind::WouldBlock {
+                    guard.clear_ready();
+                } else {
+                    eof = true;
+                }
+                break;
+            }
+        }
+        if !batch.frames.is_empty() && batch_tx.send(batch).await.is_err() {
+            break;
+        }
+        if eof {
+            break;
+        }
+    }
+
+    // Closing the queue lets the workers finish what is in flight, then the stats stage
+    drop(batch_tx);
+    Ok(stats.await.unwrap_or_default())
+}
+
+fn classify_batch(batch: &Batch) -> ShieldTally {
+    let mut tally = ShieldTally::default();
+    for range in &batch.frames {
+        let packet = &batch.data[range.clone()];
+        tally.packets += 1;
+        match PacketInfo::parse(packet).map(|info| info.transport) {
+            Some(Transport::Tcp { .. }) => tally.tcp += 1,
+            Some(Transport::Udp) => tally.udp += 1,
+            _ => {}
+        }
+        let (allowed, uid) = is_allowed(packet);
+        if allowed {
+            // Passive shield doesn't forward, it just monitors and blocks
+            tally.allowed_bytes += packet.len() as u64;
+        } else {
+            tally.dropped += 1;
+            crate::energy::blocked(packet.len());
+        }
+        match (uid, allowed) {
+            (None, _) => {}
+            (Some(_), true) => tally.lockdown_allowed += 1,
+            (Some(_), false) => tally.lockdown_dropped += 1,
+        }
+        capture::record(Direction::Outbound, uid, if allowed { "ALLOW" } else { "DROP" }, packet);
+    }
+    tally
+}
+
+// Verdict plus the UID behind it, when lockdown had to look one up
+fn is_allowed(packet: &[u8]) -> (bool, Option<u32>) {
+    let allowed = match ALLOWED_UIDS.read() {
+        Ok(guard) => guard.clone(),
+        Err(_) => return (true, None),
+    };
+    if !allowed.is_empty() {
+        lockdown_verdict(packet, &allowed, &ProcFs::system())
+    } else {
+        (check_focus_whitelist(packet), None)
+    }
+}
+
+/// Whether a packet from the apps may pass while only `allowed` UIDs have internet.
+#[cfg(test)]
+pub fn check_uid_lockdown(packet: &[u8], allowed: &[u32], source: &dyn ProcSource) -> bool {
+    lockdown_verdict(packet, allowed, source).0
+}
+
+fn lockdown_verdict(packet: &[u8], allowed: &[u32], source: &dyn ProcSource) -> (bool, Option<u32>) {
+    if allowed.is_empty() {
+        return (true, None); // Global Mode
+    }
+
+    if let Ok(value) = etherparse::SlicedPacket::from_ip(packet) {
+        let (port, is_udp) = match value.transport {
+            Some(etherparse::TransportSlice::Tcp(tcp)) => (tcp.source_port(), false),
+            Some(etherparse::TransportSlice::Udp(udp)) => (udp.source_port(), true),
+            _ => return (true, None), // Allow ICMP etc. for now
+        };
+
+        if let Some(uid) = find_uid(source, port, is_udp) {
+            // Check if UID is in allowed list; drop unauthorized traffic
+            return (allowed.contains(&uid), Some(uid));
+        }
+    }
+    (true, None) // If we can't find the UID (e.g. fast connection closure), allow it to avoid broken states
+}
+
+pub(crate) fn check_focus_whitelist(packet: &[u8]) -> bool {
+    let allowed = match ALLOWED_DOMAINS.read() {
+        Ok(guard) => guard.clone(),
+        Err(_) => return true,
+    };
+
+    if allowed.is_empty() {
+        return true;
+    }
+
+    // Real work: Minimal SNI extraction for TLS Client Hello
+    // This is a lightweight way to see where the user is going
+    if let Ok(value) = etherparse::SlicedPacket::from_ip(packet) {
+        if let Some(etherparse::TransportSlice::Tcp(tcp)) = value.transport {
+            let payload = tcp.payload();
+            if payload.len() > 43 && payload[0] == 0x16 && payload[5] == 0x01 {
+                // Potential TLS Client Hello
+                for domain in &allowed {
+                    if let Some(_pos) = payload.windows(domain.len()).position(|window| window == domain.as_bytes()) {
+                        // Found allowed domain in SNI/payload
+                        return true;
+                    }
+                }
+                return false; // SNI present but not in whitelist
+            }
+        }
+    }
+    true // Allow non-TLS or packets without SNI for now to avoid breaking basic connectivity
+}
@@ -1,40 +1,72 @@
-use std::net::{SocketAddr, TcpStream};
-use std::time::{Duration, Instant};
+use std::time::Duration;
 use serde::Serialize;
+use crate::diagnostics::{self, ConnectReport};
 
 #[derive(Serialize)]
 struct NetworkStats {
     ping: i64,
     jitter: i64,
+    // Share of the attempts that failed, in percent
+    loss: f64,
     server: String,
+    // What the target resolved to, and how long the lookup took
+    address: Option<String>,
+    resolve_ms: Option<f64>,
+    // Why nothing answered: a bad target, a failed lookup or the first failed connect
+    error: Option<String>,
 }
 
+/// Accepts a name or address, `host:port`, `[v6]:port`, a bare IPv6 address or a URL;
+/// port 80 unless one is given.
 pub fn measure_stats(target_ip_str: String) -> String {
-    let addr_str = if target_ip_str.contains(':') {
-        target_ip_str.clone()
-    } else {
-        format!("{}:80", target_ip_str)
-    };
-
-    let addr: SocketAddr = addr_str.parse().unwrap_or_else(|_| "1.1.1.1:443".parse().unwrap());
-    let timeout = Duration::from_millis(1500);
-    let mut pings = Vec::with_capacity(3);
-
-    for _ in 0..3 {
-        let start = Instant::now();
-        match TcpStream::connect_timeout(&addr, timeout) {
-            Ok(_) => pings.push(start.elapsed().as_millis() as i64),
-            Err(_) => pings.push(-1),
-        }
-    }
+    // Same probe runDiagnostics uses, three attempts back to back
+    let stop = diagnostics::cancel_token();
+    let report = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(|e| format!("RUNTIME_FAILED: {}", e))
+        .map(|rt| rt.block_on(diagnostics::connect_rtt(&target_ip_str, 3, Duration::from_millis(1500), Duration::ZERO, &stop)));
 
-    let stats = if pings.iter().any(|&p| p == -1) {
-        NetworkStats { ping: -1, jitter: 0, server: "UNREACHABLE".to_string() }
-    } else {
-        let avg_ping: i64 = pings.iter().sum::<i64>() / pings.len() as i64;
-        let jitter = pings.iter().max().unwrap_or(&0) - pings.iter().min().unwrap_or(&0);
-        NetworkStats { ping: avg_ping, jitter, server: target_ip_str }
+    // A lost attempt lowers the score through `loss`; only no answer at all is unreachable
+    let stats = match report {
+        Ok(r) if r.received > 0 => NetworkStats {
+            ping: r.avg_ms.unwrap_or(0.0).round() as i64,
+            jitter: spread_ms(&r),
+            loss: r.loss_pct,
+            server: target_ip_str,
+            address: Some(r.address),
+            resolve_ms: r.resolve_ms,
+            error: None,
+        },
+        Ok(r) => NetworkStats {
+            ping: -1,
+            jitter: 0,
+            loss: 100.0,
+            server: "UNREACHABLE".to_string(),
+            // Only set once the lookup worked
+            address: r.resolve_ms.map(|_| r.address),
+            resolve_ms: r.resolve_ms,
+            error: r.errors.into_iter().next(),
+        },
+        Err(e) => NetworkStats {
+            ping: -1,
+            jitter: 0,
+            loss: 100.0,
+            server: "UNREACHABLE".to_string(),
+            address: None,
+            resolve_ms: None,
+            error: Some(e),
+        },
     };
 
     serde_json::to_string(&stats).unwrap_or_else(|_| r#"{"ping": -1}"#.to_string())
 }
+
+/// What `jitter` has always meant here: the slowest attempt minus the fastest. The RFC 3550
+/// estimate is runDiagnostics' `jitter_ms`.
+pub(crate) fn spread_ms(report: &ConnectReport) -> i64 {
+    match (report.min_ms, report.max_ms) {
+        (Some(min), Some(max)) => (max - min).round() as i64,
+        _ => 0,
+    }
+}
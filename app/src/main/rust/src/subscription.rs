@@ -0,0 +1,229 @@
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use shadowsocks::config::{ServerAddr, ServerConfig, ServerSource};
+use shadowsocks::crypto::CipherKind;
+use shadowsocks::plugin::PluginConfig;
+use std::str::FromStr;
+
+// --- CONFIG INGESTION: SIP008 / SUBSCRIPTIONS / SSCONF ---
+// Kotlin does the fetching (ssconf:// is https:// underneath); this module only parses.
+
+/// SIP003 plugins the pool accepts, i.e. the ones the engine implements in-process (see
+/// `obfs.rs`). Anything else would make ss-local spawn a binary the device does not have.
+pub const SIP003_PLUGINS: &[&str] = &["obfs-local", "simple-obfs"];
+
+#[derive(Deserialize)]
+struct Sip008Document {
+    version: Option<u64>,
+    servers: Vec<Sip008Server>,
+}
+
+#[derive(Deserialize)]
+struct Sip008Server {
+    id: Option<String>,
+    remarks: Option<String>,
+    server: String,
+    server_port: u16,
+    password: String,
+    method: String,
+    plugin: Option<String>,
+    plugin_opts: Option<String>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct RejectedEntry {
+    pub entry: String,
+    pub reason: String,
+}
+
+#[derive(Default, Debug)]
+pub struct Subscription {
+    pub servers: Vec<ServerConfig>,
+    pub rejected: Vec<RejectedEntry>,
+}
+
+#[derive(Serialize)]
+pub struct ImportReport<'a> {
+    pub accepted: usize,
+    pub rejected: &'a [RejectedEntry],
+}
+
+impl Subscription {
+    pub fn report(&self) -> String {
+        let report = ImportReport { accepted: self.servers.len(), rejected: &self.rejected };
+        serde_json::to_string(&report).unwrap_or_else(|_| r#"{"accepted":0,"rejected":[]}"#.to_string())
+    }
+
+    fn reject(&mut self, entry: String, reason: impl Into<String>) {
+        self.rejected.push(RejectedEntry { entry, reason: reason.into() });
+    }
+}
+
+/// Parses any of the formats providers hand out:
+/// - SIP008 JSON (`{"version":1,"servers":[...]}`), as served behind `ssconf://` links
+/// - a single server object (Outline dynamic access keys)
+/// - newline separated `ss://` links, plain or as a base64 subscription blob
+pub fn parse_document(content: &str) -> Result<Subscription, String> {
+    let content = content.trim_start_matches('\u{feff}').trim();
+    if content.is_empty() {
+        return Err("EMPTY_DOCUMENT".to_string());
+    }
+
+    if content.starts_with('{') {
+        return parse_json(content);
+    }
+
+    if content.contains("://") {
+        return Ok(parse_links(content));
+    }
+
+    // Subscription blobs come in every base64 flavour there is
+    let compact: String = content.chars().filter(|c| !c.is_whitespace()).collect();
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(&compact)
+        .or_else(|_| base64::engine::general_purpose::STANDARD_NO_PAD.decode(&compact))
+        .or_else(|_| base64::engine::general_purpose::URL_SAFE.decode(&compact))
+        .or_else(|_| base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(&compact))
+        .map_err(|_| "UNKNOWN_FORMAT".to_string())?;
+    let text = String::from_utf8(decoded).map_err(|_| "UNKNOWN_FORMAT".to_string())?;
+    if !text.contains("://") {
+        return Err("UNKNOWN_FORMAT".to_string());
+    }
+    Ok(parse_links(&text))
+}
+
+fn parse_json(content: &str) -> Result<Subscription, String> {
+    let value: serde_json::Value = serde_json::from_str(content).map_err(|e| format!("INVALID_JSON: {}", e))?;
+    let mut sub = Subscription::default();
+
+    if value.get("servers").is_some() {
+        let doc: Sip008Document = serde_json::from_value(value).map_err(|e| format!("INVALID_SIP008: {}", e))?;
+        if let Some(v) = doc.version {
+            if v != 1 {
+                return Err(format!("UNSUPPORTED_SIP008_VERSION: {}", v));
+            }
+        }
+        for (i, server) in doc.servers.into_iter().enumerate() {
+            let entry = entry_name(i, server.remarks.as_deref());
+            match build_server(server) {
+                Ok(cfg) => sub.servers.push(cfg),
+                Err(reason) => sub.reject(entry, reason),
+            }
+        }
+    } else {
+        let server: Sip008Server = serde_json::from_value(value).map_err(|e| format!("INVALID_SERVER_OBJECT: {}", e))?;
+        let entry = entry_name(0, server.remarks.as_deref());
+        match build_server(server) {
+            Ok(cfg) => sub.servers.push(cfg),
+            Err(reason) => sub.reject(entry, reason),
+        }
+    }
+    Ok(sub)
+}
+
+fn parse_links(text: &str) -> Subscription {
+    let mut sub = Subscription::default();
+    let lines = text.lines().map(str::trim).filter(|l| !l.is_empty() && !l.starts_with('#'));
+    for (i, line) in lines.enumerate() {
+        let entry = entry_name(i, None);
+        if line.starts_with("ssconf://") {
+            sub.reject(entry, "NESTED_SSCONF_NOT_FETCHED");
+            continue;
+        }
+        if !line.starts_with("ss://") {
+            sub.reject(entry, "UNSUPPORTED_SCHEME");
+            continue;
+        }
+        match ServerConfig::from_url(line) {
+            Ok(cfg) => match validate_server(&cfg) {
+                Ok(()) => sub.servers.push(cfg),
+                Err(reason) => sub.reject(entry_name(i, cfg.remarks()), reason),
+            },
+            Err(e) => sub.reject(entry, format!("INVALID_SS_URL: {}", e)),
+        }
+    }
+    sub
+}
+
+fn build_server(server: Sip008Server) -> Result<ServerConfig, String> {
+    if server.server.trim().is_empty() {
+        return Err("MISSING_SERVER".to_string());
+    }
+    if server.server_port == 0 {
+        return Err("INVALID_PORT".to_string());
+    }
+    let method = parse_cipher(&server.method)?;
+    let addr = ServerAddr::from_str(&format!("{}:{}", bracket_host(server.server.trim()), server.server_port))
+        .map_err(|_| "INVALID_SERVER".to_string())?;
+
+    let mut cfg = ServerConfig::new(addr, server.password, method).map_err(|e| format!("INVALID_PASSWORD: {}", e))?;
+    if let Some(plugin) = server.plugin.filter(|p| !p.is_empty()) {
+        cfg.set_plugin(PluginConfig {
+            plugin,
+            plugin_opts: server.plugin_opts.filter(|o| !o.is_empty()),
+            plugin_args: Vec::new(),
+            plugin_mode: shadowsocks::config::Mode::TcpOnly,
+        });
+    }
+    if let Some(remarks) = server.remarks {
+        cfg.set_remarks(remarks);
+    }
+    if let Some(id) = server.id {
+        cfg.set_id(id);
+    }
+    cfg.set_source(ServerSource::OnlineConfig);
+    validate_server(&cfg)?;
+    Ok(cfg)
+}
+
+fn parse_cipher(method: &str) -> Result<CipherKind, String> {
+    let kind = CipherKind::from_str(method).map_err(|_| format!("UNSUPPORTED_CIPHER: {}", method))?;
+    if kind.is_none() {
+        return Err(format!("INSECURE_CIPHER: {}", method));
+    }
+    Ok(kind)
+}
+
+fn validate_server(cfg: &ServerConfig) -> Result<(), String> {
+    if cfg.method().is_none() {
+        return Err(format!("INSECURE_CIPHER: {}", cfg.method()));
+    }
+    if cfg.password().is_empty() && !cfg.method().is_aead_2022() {
+        return Err("EMPTY_PASSWORD".to_string());
+    }
+    if let Some(plugin) = cfg.plugin() {
+        validate_plugin(&plugin.plugin, plugin.plugin_opts.as_deref())?;
+    }
+    Ok(())
+}
+
+pub fn validate_plugin(name: &str, opts: Option<&str>) -> Result<(), String> {
+    if !SIP003_PLUGINS.contains(&name) {
+        return Err(format!("UNSUPPORTED_PLUGIN: {}", name));
+    }
+    // SIP003 options are `key=value` pairs separated by `;`, bare flags allowed
+    if let Some(opts) = opts {
+        for opt in opts.split(';').filter(|o| !o.is_empty()) {
+            let key = opt.split('=').next().unwrap_or("");
+            if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+                return Err(format!("INVALID_PLUGIN_OPTS: {}", opt));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn entry_name(index: usize, remarks: Option<&str>) -> String {
+    match remarks {
+        Some(r) if !r.is_empty() => format!("#{} {}", index, r),
+        _ => format!("#{}", index),
+    }
+}
+
+fn bracket_host(host: &str) -> String {
+    if host.contains(':') && !host.starts_with('[') {
+        format!("[{}]", host)
+    } else {
+        host.to_string()
+    }
+}
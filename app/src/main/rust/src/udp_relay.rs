@@ -0,0 +1,198 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use crate::upstream::ProxyAuth;
+
+// --- UDP RELAY HEALTH & UDP-OVER-TCP FALLBACK ---
+// Plenty of networks (and some servers) drop UDP. We probe the SOCKS5 UDP relay with a real
+// DNS query and, if nothing comes back, hand tun2proxy a udpgw server so UDP is carried
+// inside the TCP proxy connection instead.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum UdpMode {
+    Unknown = 0,
+    Native = 1,
+    UdpOverTcp = 2,
+    Unavailable = 3,
+}
+
+impl UdpMode {
+    pub fn from_u8(v: u8) -> Self {
+        match v {
+            1 => UdpMode::Native,
+            2 => UdpMode::UdpOverTcp,
+            3 => UdpMode::Unavailable,
+            _ => UdpMode::Unknown,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            UdpMode::Unknown => "UNKNOWN",
+            UdpMode::Native => "NATIVE",
+            UdpMode::UdpOverTcp => "UDP_OVER_TCP",
+            UdpMode::Unavailable => "UNAVAILABLE",
+        }
+    }
+}
+
+/// Picks the UDP mode for a session from the probe result and whether a udpgw is configured.
+pub fn decide_mode(relay_ok: bool, gateway: Option<SocketAddr>) -> UdpMode {
+    match (relay_ok, gateway) {
+        (true, _) => UdpMode::Native,
+        (false, Some(_)) => UdpMode::UdpOverTcp,
+        (false, None) => UdpMode::Unavailable,
+    }
+}
+
+/// Sends one DNS query through the proxy's UDP ASSOCIATE relay and waits for any reply.
+/// Returns the round trip time on success.
+pub async fn probe_socks5_udp(
+    proxy: SocketAddr,
+    auth: Option<&ProxyAuth>,
+    target: SocketAddr,
+    timeout: Duration,
+) -> io::Result<Duration> {
+    tokio::time::timeout(timeout, probe_inner(proxy, auth, target))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "UDP_RELAY_TIMEOUT"))?
+}
+
+async fn probe_inner(proxy: SocketAddr, auth: Option<&ProxyAuth>, target: SocketAddr) -> io::Result<Duration> {
+    // The control connection must stay open for the lifetime of the association
+    let mut control = TcpStream::connect(proxy).await?;
+    socks5_handshake(&mut control, auth).await?;
+
+    // UDP ASSOCIATE, client address unknown yet
+    control.write_all(&[0x05, 0x03, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).await?;
+    let mut relay = read_socks5_reply(&mut control).await?;
+    if relay.ip().is_unspecified() {
+        relay.set_ip(proxy.ip());
+    }
+
+    let bind: SocketAddr = if relay.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (std::net::Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(relay).await?;
+
+    let mut datagram = socks5_udp_header(target);
+    datagram.extend_from_slice(&dns_probe_query());
+
+    let start = Instant::now();
+    socket.send(&datagram).await?;
+    let mut buf = [0u8; 1500];
+    let n = socket.recv(&mut buf).await?;
+    if n <= 10 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "UDP_RELAY_SHORT_REPLY"));
+    }
+    Ok(start.elapsed())
+}
+
+pub(crate) async fn socks5_handshake(stream: &mut TcpStream, auth: Option<&ProxyAuth>) -> io::Result<()> {
+    let method = if auth.is_some() { 0x02 } else { 0x00 };
+    stream.write_all(&[0x05, 0x01, method]).await?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != 0x05 || reply[1] != method {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "SOCKS5_METHOD_REJECTED"));
+    }
+    if let Some(auth) = auth {
+        // RFC 1929 username/password
+        let mut req = vec![0x01, auth.username.len() as u8];
+        req.extend_from_slice(auth.username.as_bytes());
+        req.push(auth.password.len() as u8);
+        req.extend_from_slice(auth.password.as_bytes());
+        stream.write_all(&req).await?;
+        stream.read_exact(&mut reply).await?;
+        if reply[1] != 0x00 {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "SOCKS5_AUTH_FAILED"));
+        }
+    }
+    Ok(())
+}
+
+pub(crate) async fn read_socks5_reply(stream: &mut TcpStream) -> io::Result<SocketAddr> {
+    let mut head = [0u8; 4];
+    stream.read_exact(&mut head).await?;
+    if head[1] != 0x00 {
+        return Err(io::Error::new(io::ErrorKind::ConnectionRefused, format!("SOCKS5_REPLY_{}", head[1])));
+    }
+    let ip: IpAddr = match head[3] {
+        0x01 => {
+            let mut a = [0u8; 4];
+            stream.read_exact(&mut a).await?;
+            a.into()
+        }
+        0x04 => {
+            let mut a = [0u8; 16];
+            stream.read_exact(&mut a).await?;
+            a.into()
+        }
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "SOCKS5_BAD_ATYP")),
+    };
+    let mut port = [0u8; 2];
+    stream.read_exact(&mut port).await?;
+    Ok(SocketAddr::new(ip, u16::from_be_bytes(port)))
+}
+
+/// CONNECT to `host:port`; names go to the proxy unresolved, so it does the lookup.
+pub(crate) async fn socks5_connect(stream: &mut TcpStream, host: &str, port: u16) -> io::Result<SocketAddr> {
+    let mut req = vec![0x05, 0x01, 0x00];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(v4)) => {
+            req.push(0x01);
+            req.extend_from_slice(&v4.octets());
+        }
+        Ok(IpAddr::V6(v6)) => {
+            req.push(0x04);
+            req.extend_from_slice(&v6.octets());
+        }
+        Err(_) => {
+            let name = host.as_bytes();
+            if name.is_empty() || name.len() > 255 {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "SOCKS5_BAD_HOST"));
+            }
+            req.push(0x03);
+            req.push(name.len() as u8);
+            req.extend_from_slice(name);
+        }
+    }
+    req.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&req).await?;
+    read_socks5_reply(stream).await
+}
+
+fn socks5_udp_header(target: SocketAddr) -> Vec<u8> {
+    let mut out = vec![0x00, 0x00, 0x00];
+    match target.ip() {
+        IpAddr::V4(v4) => {
+            out.push(0x01);
+            out.extend_from_slice(&v4.octets());
+        }
+        IpAddr::V6(v6) => {
+            out.push(0x04);
+            out.extend_from_slice(&v6.octets());
+        }
+    }
+    out.extend_from_slice(&target.port().to_be_bytes());
+    out
+}
+
+/// Minimal `A example.com` query; any answer (even NXDOMAIN) proves the relay works.
+fn dns_probe_query() -> Vec<u8> {
+    let mut q = Vec::with_capacity(29);
+    q.extend_from_slice(&rand::random::<u16>().to_be_bytes());
+    q.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+    for label in ["example", "com"] {
+        q.push(label.len() as u8);
+        q.extend_from_slice(label.as_bytes());
+    }
+    q.extend_from_slice(&[0x00, 0x00, 0x01, 0x00, 0x01]);
+    q
+}
@@ -0,0 +1,149 @@
+use shadowsocks::config::ServerConfig;
+use url::Url;
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+// --- UPSTREAM: WHERE THE TUNNEL EGRESSES ---
+
+#[derive(Zeroize, ZeroizeOnDrop, Clone, PartialEq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProxyAuth {
+    pub username: String,
+    pub password: String,
+}
+
+// Upstreams end up in logs on failure; never print the password
+impl std::fmt::Debug for ProxyAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ProxyAuth").field("username", &self.username).field("password", &"***").finish()
+    }
+}
+
+#[derive(Clone)]
+pub enum Upstream {
+    /// Encrypted egress through an embedded ss-local instance balancing across the servers.
+    Shadowsocks(Vec<ServerConfig>),
+    /// Existing SOCKS5 proxy, handed to tun2proxy directly.
+    Socks5 { host: String, port: u16, auth: Option<ProxyAuth> },
+    /// Existing HTTP proxy (CONNECT), handed to tun2proxy directly.
+    Http { host: String, port: u16, auth: Option<ProxyAuth> },
+}
+
+impl Upstream {
+    /// Parses the key handed over by `setOutlineKey`. The URL scheme picks the upstream:
+    /// `ss://`, `socks5://[user[:pass]@]host:port` or `http://[user[:pass]@]host:port`.
+    pub fn from_url(raw: &str) -> Result<Self, String> {
+        let raw = raw.trim();
+        let scheme = raw.split("://").next().unwrap_or("").to_ascii_lowercase();
+
+        match scheme.as_str() {
+            "ss" => {
+                let cfg = ServerConfig::from_url(raw).map_err(|e| format!("INVALID_SS_KEY: {}", e))?;
+                // `plugin=` from the URL, e.g. `obfs-local;obfs=tls;obfs-host=...`
+                if let Some(plugin) = cfg.plugin() {
+                    crate::subscription::validate_plugin(&plugin.plugin, plugin.plugin_opts.as_deref())?;
+                }
+                Ok(Upstream::Shadowsocks(vec![cfg]))
+            }
+            "socks5" | "socks5h" => {
+                let (host, port, auth) = parse_proxy_url(raw, Some(1080))?;
+                Ok(Upstream::Socks5 { host, port, auth })
+            }
+            "http" => {
+                // `http` has a known default port, so the URL always yields one
+                let (host, port, auth) = parse_proxy_url(raw, None)?;
+                Ok(Upstream::Http { host, port, auth })
+            }
+            "" => Err("INVALID_UPSTREAM: MISSING_SCHEME".to_string()),
+            other => Err(format!("INVALID_UPSTREAM: UNSUPPORTED_SCHEME_{}", other.to_ascii_uppercase())),
+        }
+    }
+
+    /// Short label for the console, never includes credentials.
+    pub fn label(&self) -> String {
+        match self {
+            Upstream::Shadowsocks(servers) => match servers.as_slice() {
+                [] => "ss://<empty pool>".to_string(),
+                [one] => format!("ss://{}", one.addr()),
+                [first, rest @ ..] => format!("ss://{} (+{} pooled)", first.addr(), rest.len()),
+            },
+            Upstream::Socks5 { host, port, .. } => format!("socks5://{}", host_port(host, *port)),
+            Upstream::Http { host, port, .. } => format!("http://{}", host_port(host, *port)),
+        }
+    }
+
+    /// Address of the one shadowsocks server behind this upstream. A pool has none: ss-local
+    /// picks a server for each connection, so nothing measured through it belongs to one.
+    pub fn server(&self) -> Option<String> {
+        match self {
+            Upstream::Shadowsocks(servers) => match servers.as_slice() {
+                [one] => Some(one.addr().to_string()),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Proxy URL in the form tun2proxy's `ArgProxy` expects. Shadowsocks upstreams have no
+    /// URL of their own; tun2proxy is pointed at the ss-local SOCKS5 listener instead.
+    pub fn proxy_url(&self) -> Option<String> {
+        let (scheme, host, port, auth) = match self {
+            Upstream::Shadowsocks(_) => return None,
+            Upstream::Socks5 { host, port, auth } => ("socks5", host, *port, auth),
+            Upstream::Http { host, port, auth } => ("http", host, *port, auth),
+        };
+        let userinfo = match auth {
+            Some(a) => format!("{}:{}@", encode_userinfo(&a.username), encode_userinfo(&a.password)),
+            None => String::new(),
+        };
+        Some(format!("{}://{}{}", scheme, userinfo, host_port(host, port)))
+    }
+}
+
+// Shadowsocks server configs carry the key, so only the label is printed
+impl std::fmt::Debug for Upstream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Upstream").field(&self.label()).finish()
+    }
+}
+
+fn parse_proxy_url(raw: &str, fallback_port: Option<u16>) -> Result<(String, u16, Option<ProxyAuth>), String> {
+    let url = Url::parse(raw).map_err(|e| format!("INVALID_UPSTREAM: {}", e))?;
+
+    let host = match url.host_str() {
+        Some(h) if !h.is_empty() => h.trim_start_matches('[').trim_end_matches(']').to_string(),
+        _ => return Err("INVALID_UPSTREAM: MISSING_HOST".to_string()),
+    };
+    let port = url
+        .port_or_known_default()
+        .or(fallback_port)
+        .ok_or_else(|| "INVALID_UPSTREAM: MISSING_PORT".to_string())?;
+
+    let auth = if url.username().is_empty() && url.password().is_none() {
+        None
+    } else {
+        let username = decode_userinfo(url.username())?;
+        let password = decode_userinfo(url.password().unwrap_or(""))?;
+        Some(ProxyAuth { username, password })
+    };
+
+    Ok((host, port, auth))
+}
+
+fn decode_userinfo(s: &str) -> Result<String, String> {
+    percent_encoding::percent_decode_str(s)
+        .decode_utf8()
+        .map(|c| c.into_owned())
+        .map_err(|_| "INVALID_UPSTREAM: BAD_CREDENTIALS".to_string())
+}
+
+fn encode_userinfo(s: &str) -> String {
+    percent_encoding::utf8_percent_encode(s, percent_encoding::NON_ALPHANUMERIC).to_string()
+}
+
+fn host_port(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
@@ -2,153 +2,103 @@ use std::time::Duration;
 use shadowsocks::config::{ServerConfig, Mode, ServerAddr};
 use shadowsocks_service::config::{Config, ConfigType, LocalInstanceConfig, LocalConfig, ProtocolType, ServerInstanceConfig};
 use shadowsocks_service::local::run as run_ss_local;
-use tun2proxy::{run as run_tun2proxy, Args, ArgProxy, ArgDns, ArgVerbosity, CancellationToken};
+use tun2proxy::{run as run_tun2proxy, Args, ArgProxy, ArgDns, ArgVerbosity, CancellationToken, ProxyType};
 use std::str::FromStr;
 use std::convert::TryFrom;
-use std::sync::atomic::Ordering;
-use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::net::SocketAddr;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
 use tokio::io::unix::AsyncFd;
+use tokio::io::Interest;
+use tokio::sync::mpsc;
 use std::net::TcpListener;
 use crate::common::*;
+use crate::upstream::{ProxyAuth, Upstream};
+use crate::udp_relay::{decide_mode, probe_socks5_udp, socks5_connect, socks5_handshake, UdpMode};
+use crate::obfs::{spawn_obfs_local, ObfsConfig};
+use crate::filter::FilteredTun;
+use crate::config::{EngineConfig, TunConfig};
+use crate::health;
+use crate::killswitch;
+use crate::shield::ShieldConfig;
 
 use std::pin::Pin;
 use std::task::{Context as TaskContext, Poll};
-use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+// --- DIRECT PATH: PROXY-LESS STACK FOR FLOWS ROUTED AROUND THE TUNNEL ---
+// The app process is excluded from the VPN, so sockets opened by this stack leave the device
+// directly. Replies are written straight back into the TUN through a dup of its fd.
+struct DirectTun {
+    rx: mpsc::Receiver<Vec<u8>>,
+    writer: AsyncFd<OwnedFd>,
+}
 
-// --- TRUE LOCKDOWN: FILTERED TUN WRAPPER ---
-struct FilteredTun<T> {
-    inner: T,
+impl DirectTun {
+    fn new(fd: RawFd, rx: mpsc::Receiver<Vec<u8>>) -> std::io::Result<Self> {
+        let dup = unsafe { libc::dup(fd) };
+        if dup < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let owned = unsafe { OwnedFd::from_raw_fd(dup) };
+        let writer = AsyncFd::with_interest(owned, Interest::WRITABLE)?;
+        Ok(DirectTun { rx, writer })
+    }
 }
 
-impl<T: AsyncRead + Unpin> AsyncRead for FilteredTun<T> {
+impl AsyncRead for DirectTun {
     fn poll_read(
         mut self: Pin<&mut Self>,
         cx: &mut TaskContext<'_>,
         buf: &mut ReadBuf<'_>,
     ) -> Poll<std::io::Result<()>> {
-        loop {
-            let initial_len = buf.filled().len();
-            match Pin::new(&mut self.inner).poll_read(cx, buf) {
-                Poll::Ready(Ok(())) => {
-                    let packet = &buf.filled()[initial_len..];
-                    if packet.is_empty() { return Poll::Ready(Ok(())); }
-                    
-                    if check_uid_lockdown(packet) {
-                        return Poll::Ready(Ok(())); // Allow
-                    } else {
-                        // Drop & Retry: Clear the buffer portion and read again
-                        let current_len = buf.filled().len();
-                        buf.set_filled(initial_len); 
-                        // Note: To be truly stable, we'd loop. For now, we continue
-                        continue;
-                    }
-                }
-                other => return other,
+        match self.rx.poll_recv(cx) {
+            Poll::Ready(Some(packet)) => {
+                let n = packet.len().min(buf.remaining());
+                buf.put_slice(&packet[..n]);
+                Poll::Ready(Ok(()))
             }
+            Poll::Ready(None) => Poll::Ready(Ok(())),
+            Poll::Pending => Poll::Pending,
         }
     }
 }
 
-impl<T: AsyncWrite + Unpin> AsyncWrite for FilteredTun<T> {
+impl AsyncWrite for DirectTun {
     fn poll_write(
-        mut self: Pin<&mut Self>,
+        self: Pin<&mut Self>,
         cx: &mut TaskContext<'_>,
         buf: &[u8],
     ) -> Poll<std::io::Result<usize>> {
-        Pin::new(&mut self.inner).poll_write(cx, buf)
-    }
-
-    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
-        Pin::new(&mut self.inner).poll_flush(cx)
-    }
-
-    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
-        Pin::new(&mut self.inner).poll_shutdown(cx)
-    }
-}
-
-fn find_uid_by_port(port: u16, is_udp: bool) -> Option<u32> {
-    use std::fs::File;
-    use std::io::{BufRead, BufReader};
-
-    let path = if is_udp { "/proc/net/udp" } else { "/proc/net/tcp" };
-    if let Ok(file) = File::open(path) {
-        let reader = BufReader::new(file);
-        // Skip header
-        for line in reader.lines().skip(1).flatten() {
-            let parts: Vec<&str> = line.split_whitespace().collect();
-            if parts.len() > 7 {
-                // local_address is at parts[1], format: "0100007F:2710" (hex IP:port)
-                if let Some(pos) = parts[1].find(':') {
-                    if let Ok(p) = u16::from_str_radix(&parts[1][pos+1..], 16) {
-                        if p == port {
-                            return parts[7].parse::<u32>().ok();
-                        }
-                    }
+        loop {
+            let mut guard = match self.writer.poll_write_ready(cx) {
+                Poll::Ready(Ok(guard)) => guard,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            };
+            let result = guard.try_io(|fd| {
+                let n = unsafe { libc::write(fd.as_raw_fd(), buf.as_ptr() as *const libc::c_void, buf.len()) };
+                if n < 0 {
+                    Err(std::io::Error::last_os_error())
+                } else {
+                    Ok(n as usize)
                 }
+            });
+            match result {
+                Ok(res) => return Poll::Ready(res),
+                Err(_would_block) => continue,
             }
         }
     }
-    None
-}
-
-fn check_uid_lockdown(packet: &[u8]) -> bool {
-    let allowed = match ALLOWED_UIDS.read() {
-        Ok(guard) => guard.clone(),
-        Err(_) => return true,
-    };
-
-    if allowed.is_empty() {
-        return true; // Global Mode
-    }
-
-    if let Ok(value) = etherparse::SlicedPacket::from_ip(packet) {
-        let (port, is_udp) = match value.transport {
-            Some(etherparse::TransportSlice::Tcp(tcp)) => (tcp.source_port(), false),
-            Some(etherparse::TransportSlice::Udp(udp)) => (udp.source_port(), true),
-            _ => return true, // Allow ICMP etc. for now
-        };
-
-        if let Some(uid) = find_uid_by_port(port, is_udp) {
-            // Check if UID is in allowed list
-            if allowed.contains(&uid) {
-                return true;
-            } else {
-                return false; // Drop unauthorized traffic
-            }
-        }
-    }
-    true // If we can't find the UID (e.g. fast connection closure), allow it to avoid broken states
-}
 
-fn check_focus_whitelist(packet: &[u8]) -> bool {
-    let allowed = match ALLOWED_DOMAINS.read() {
-        Ok(guard) => guard.clone(),
-        Err(_) => return true,
-    };
-
-    if allowed.is_empty() {
-        return true;
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
     }
 
-    // Real work: Minimal SNI extraction for TLS Client Hello
-    // This is a lightweight way to see where the user is going
-    if let Ok(value) = etherparse::SlicedPacket::from_ip(packet) {
-        if let Some(etherparse::TransportSlice::Tcp(tcp)) = value.transport {
-            let payload = tcp.payload();
-            if payload.len() > 43 && payload[0] == 0x16 && payload[5] == 0x01 {
-                // Potential TLS Client Hello
-                for domain in &allowed {
-                    if let Some(_pos) = payload.windows(domain.len()).position(|window| window == domain.as_bytes()) {
-                        // Found allowed domain in SNI/payload
-                        return true;
-                    }
-                }
-                return false; // SNI present but not in whitelist
-            }
-        }
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
     }
-    true // Allow non-TLS or packets without SNI for now to avoid breaking basic connectivity
 }
 
 fn find_free_port() -> Option<u16> {
@@ -171,7 +121,7 @@ fn set_nonblocking(fd: RawFd) -> std::io::Result<()> {
     Ok(())
 }
 
-pub async fn run_passive_shield_internal(fd: RawFd) {
+pub async fn run_passive_shield_internal(fd: RawFd, stop: CancellationToken) {
     CORE_STATUS.store(2, Ordering::SeqCst);
     crate::log_to_java("VPN >> PASSIVE_SHIELD_UP");
     
@@ -179,74 +129,295 @@ pub async fn run_passive_shield_internal(fd: RawFd) {
         crate::log_to_java(&format!("VPN >> ERR_NONBLOCK: {}", e));
     }
 
-    let async_fd = match AsyncFd::new(fd) {
-        Ok(afd) => afd,
+    let config = ShieldConfig { workers: crate::runtime::current().shield_workers(), ..ShieldConfig::default() };
+    match crate::shield::run(fd, config, &stop).await {
+        Ok(tally) => crate::log_to_java(&format!(
+            "VPN >> PASSIVE_SHIELD_STATS: PACKETS_{} DROPPED_{}",
+            tally.packets, tally.dropped
+        )),
         Err(e) => {
             crate::log_to_java(&format!("VPN >> PASSIVE_ERR: {}", e));
+            health::record_error("PASSIVE_SHIELD_FAILED", e.to_string());
             CORE_STATUS.store(3, Ordering::SeqCst);
             return;
         }
+    }
+    crate::log_to_java("VPN >> PASSIVE_SHIELD_DOWN");
+    CORE_STATUS.store(0, Ordering::SeqCst);
+}
+
+/// Routes servers carrying a simple-obfs plugin through the in-process transport. TCP goes
+/// to a loopback obfs listener while UDP keeps going straight to the server, which is what
+/// obfs-local does too. With `stealth` on, servers without obfuscation are left out.
+async fn apply_plugins(servers: Vec<ServerConfig>, stealth: bool) -> Result<Vec<ServerConfig>, String> {
+    let mut out = Vec::with_capacity(servers.len() * 2);
+    for cfg in servers {
+        let plugin = match cfg.plugin() {
+            Some(p) => p.clone(),
+            None if stealth => {
+                crate::log_to_java(&format!("STEALTH >> SKIP_PLAIN_SERVER: {}", cfg.addr()));
+                continue;
+            }
+            None => {
+                out.push(cfg);
+                continue;
+            }
+        };
+        crate::subscription::validate_plugin(&plugin.plugin, plugin.plugin_opts.as_deref())?;
+        if !cfg.identity_keys().is_empty() {
+            return Err("OBFS_EIH_UNSUPPORTED".to_string());
+        }
+
+        let obfs_cfg = ObfsConfig::from_plugin_opts(plugin.plugin_opts.as_deref())?;
+        let obfs_addr = spawn_obfs_local(obfs_cfg.clone(), cfg.addr().to_string())
+            .await
+            .map_err(|e| format!("OBFS_BIND_FAILED: {}", e))?;
+        crate::log_to_java(&format!("STEALTH >> OBFS_{:?}_ON_{}", obfs_cfg.mode, obfs_addr).to_uppercase());
+
+        let mut tcp = ServerConfig::new(obfs_addr, cfg.password().to_owned(), cfg.method())
+            .map_err(|e| format!("OBFS_CONFIG: {}", e))?;
+        tcp.set_mode(Mode::TcpOnly);
+        let mut udp = ServerConfig::new(cfg.addr().clone(), cfg.password().to_owned(), cfg.method())
+            .map_err(|e| format!("OBFS_CONFIG: {}", e))?;
+        udp.set_mode(Mode::UdpOnly);
+        if let Some(remarks) = cfg.remarks() {
+            tcp.set_remarks(remarks);
+            udp.set_remarks(remarks);
+        }
+        out.push(tcp);
+        out.push(udp);
+    }
+
+    if out.is_empty() {
+        return Err(if stealth { "STEALTH_NO_OBFS_SERVER" } else { "EMPTY_SERVER_POOL" }.to_string());
+    }
+    Ok(out)
+}
+
+/// Spawns an embedded ss-local for `servers` and waits until its SOCKS5 listener
+/// accepts connections. Returns the listener address.
+async fn start_ss_local(servers: Vec<ServerConfig>, port: u16) -> Option<String> {
+    let port = if port != 0 { port } else { find_free_port().unwrap_or(10808) };
+    PROXY_PORT.store(port, Ordering::Relaxed);
+
+    let local_addr_str = format!("127.0.0.1:{}", port);
+    let ss_local_addr = local_addr_str.clone();
+    tokio::spawn(async move {
+        let mut config = Config::new(ConfigType::Local);
+        if let Ok(local_addr) = ServerAddr::from_str(&ss_local_addr) {
+            let mut local_config = LocalConfig::new(ProtocolType::Socks);
+            local_config.addr = Some(local_addr);
+            local_config.mode = Mode::TcpAndUdp;
+            let acl = SS_ACL.read().ok().and_then(|acl| acl.clone());
+            if acl.is_some() {
+                crate::log_to_java("VPN >> SS_ACL_ACTIVE");
+            }
+            config.local.push(LocalInstanceConfig { config: local_config, acl });
+            for server_config in servers {
+                config.server.push(ServerInstanceConfig::with_server_config(server_config));
+            }
+            crate::log_to_java(&format!("VPN >> SS_LOCAL_READY_ON_{}", ss_local_addr));
+            if let Err(e) = run_ss_local(config).await {
+                crate::log_to_java(&format!("VPN >> SS_ERR: {}", e));
+                health::record_error("SS_LOCAL_FAILED", e.to_string());
+            }
+            // ss-local never returns while it is serving
+            killswitch::set_upstream_up(false);
+        }
+    });
+
+    // Wait for SOCKS5 proxy to be ready with retries
+    for i in 1..=10 {
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        if tokio::net::TcpStream::connect(local_addr_str.clone()).await.is_ok() {
+            return Some(local_addr_str);
+        }
+        crate::log_to_java(&format!("VPN >> WAITING_FOR_SOCKS5 ({}/10)", i));
+    }
+    None
+}
+
+/// Probes the SOCKS5 UDP relay of `proxy`. HTTP proxies have no UDP relay at all.
+async fn probe_udp_relay(proxy: &ArgProxy) -> bool {
+    if proxy.proxy_type != ProxyType::Socks5 {
+        return false;
+    }
+    let auth = proxy.credentials.as_ref().map(|c| ProxyAuth {
+        username: c.username.clone(),
+        password: c.password.clone(),
+    });
+    let dns: SocketAddr = (Args::default().dns_addr, 53).into();
+    match probe_socks5_udp(proxy.addr, auth.as_ref(), dns, Duration::from_secs(3)).await {
+        Ok(rtt) => {
+            crate::log_to_java(&format!("VPN >> UDP_RELAY_OK: {}ms", rtt.as_millis()));
+            true
+        }
+        Err(e) => {
+            crate::log_to_java(&format!("VPN >> UDP_RELAY_FAILED: {}", e));
+            health::record_error("UDP_RELAY_FAILED", e.to_string());
+            false
+        }
+    }
+}
+
+// How often the session monitor checks the upstream; tests do not wait out the real interval
+#[cfg(not(test))]
+const MONITOR_TICK: Duration = Duration::from_secs(5);
+#[cfg(test)]
+const MONITOR_TICK: Duration = Duration::from_millis(500);
+
+/// Opens a connection to `host:port` through `proxy`. Reaching ss-local's listener says
+/// nothing about the server behind it; a CONNECT fails once that server is gone.
+async fn probe_remote_path(proxy: &ArgProxy, host: &str, port: u16) -> std::io::Result<()> {
+    let auth = proxy.credentials.as_ref().map(|c| ProxyAuth {
+        username: c.username.clone(),
+        password: c.password.clone(),
+    });
+    let mut stream = tokio::net::TcpStream::connect(proxy.addr).await?;
+    if proxy.proxy_type != ProxyType::Http {
+        socks5_handshake(&mut stream, auth.as_ref()).await?;
+        return socks5_connect(&mut stream, host, port).await.map(|_| ());
+    }
+
+    let authority = if host.contains(':') { format!("[{}]:{}", host, port) } else { format!("{}:{}", host, port) };
+    let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", authority);
+    if let Some(auth) = &auth {
+        use base64::Engine;
+        let basic = base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", auth.username, auth.password));
+        request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", basic));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // Only the status line matters; the proxy sends nothing past the head until we do
+    let mut head = Vec::new();
+    let mut chunk = [0u8; 512];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") && head.len() < 8192 {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        head.extend_from_slice(&chunk[..n]);
+    }
+    let status = head.split(|b| *b == b' ').nth(1).unwrap_or_default();
+    if status != b"200" {
+        return Err(std::io::Error::other(format!("HTTP_CONNECT_{}", String::from_utf8_lossy(status))));
+    }
+    Ok(())
+}
+
+/// Brings up the configured upstream and returns the proxy tun2proxy should talk to.
+async fn prepare_upstream(config: &EngineConfig, pool: Vec<ServerConfig>) -> Result<ArgProxy, String> {
+    // An explicit key wins over the subscription pool
+    let upstream = if config.key.key.is_empty() {
+        Upstream::Shadowsocks(pool)
+    } else {
+        Upstream::from_url(&config.key.key)?
+    };
+    crate::log_to_java(&format!("VPN >> UPSTREAM: {}", upstream.label()));
+    health::set_upstream(upstream.label(), upstream.server());
+
+    let proxy_url = match upstream {
+        Upstream::Shadowsocks(servers) => {
+            let servers = apply_plugins(servers, config.stealth).await?;
+            match start_ss_local(servers, config.proxy_port).await {
+                Some(local_addr_str) => format!("socks5://{}", local_addr_str),
+                None => return Err("ERR: SOCKS5_TIMEOUT".to_string()),
+            }
+        }
+        // tun2proxy speaks SOCKS5 and HTTP itself, no local relay needed
+        other => other.proxy_url().unwrap_or_default(),
     };
 
-    let mut buf = vec![0u8; 16384];
-    loop {
-        if CORE_STATUS.load(Ordering::SeqCst) == 0 { break; }
-        match async_fd.readable().await {
-            Ok(mut guard) => {
-                match unsafe { libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) } {
-                    n if n > 0 => {
-                        let n_usize = n as usize;
-                        let packet = &buf[..n_usize];
-                        
-                        let is_allowed = {
-                            let allowed_uids = match ALLOWED_UIDS.read() {
-                                Ok(guard) => guard.clone(),
-                                Err(_) => Vec::new(),
-                            };
-                            
-                            if !allowed_uids.is_empty() {
-                                check_uid_lockdown(packet)
-                            } else {
-                                match ALLOWED_DOMAINS.read() {
-                                    Ok(guard) => {
-                                        if guard.is_empty() {
-                                            true
-                                        } else {
-                                            check_focus_whitelist(packet)
-                                        }
-                                    },
-                                    Err(_) => true,
-                                }
-                            }
-                        };
+    ArgProxy::try_from(proxy_url.as_str()).map_err(|_| "ERR: INVALID_PROXY_URL".to_string())
+}
 
-                        if is_allowed {
-                            BYTES_PROCESSED.fetch_add(n_usize as u64, Ordering::Relaxed);
-                            // Passive shield doesn't forward, it just monitors and blocks.
-                        } else {
-                            crate::log_to_java("SHIELD >> TRAFFIC_DROPPED: UNAUTHORIZED_UID");
-                        }
-                        
-                        OTHER_COUNT.fetch_add(1, Ordering::Relaxed);
-                        guard.clear_ready();
-                    }
-                    0 => break,
-                    _ => {
-                        let err = std::io::Error::last_os_error();
-                        if err.kind() != std::io::ErrorKind::WouldBlock {
-                            break;
-                        }
-                    }
+/// Starts the proxy-less stack that serves Direct flows for the whole session.
+fn spawn_direct_stack(fd: RawFd, tun: &TunConfig, token: CancellationToken) -> Option<mpsc::Sender<Vec<u8>>> {
+    let (direct_tx, direct_rx) = mpsc::channel(512);
+    match DirectTun::new(fd, direct_rx) {
+        Ok(direct_tun) => {
+            let mut direct_args = Args::default();
+            direct_args.dns(ArgDns::Direct).verbosity(ArgVerbosity::Off).ipv6_enabled(tun.ipv6.is_some());
+            let mtu = tun.mtu;
+            if let Ok(none) = ArgProxy::try_from("none") {
+                direct_args.proxy(none);
+            }
+            tokio::spawn(async move {
+                if let Err(e) = run_tun2proxy(direct_tun, mtu, direct_args, token).await {
+                    crate::log_to_java(&format!("ROUTE >> DIRECT_STACK_EXIT: {}", e));
+                    health::record_error("DIRECT_STACK_EXIT", e.to_string());
                 }
+            });
+            Some(direct_tx)
+        }
+        Err(e) => {
+            crate::log_to_java(&format!("ROUTE >> DIRECT_STACK_UNAVAILABLE: {}", e));
+            None
+        }
+    }
+}
+
+type Drain = (CancellationToken, tokio::task::JoinHandle<()>);
+
+/// With the kill switch on, keeps reading (and dropping) TUN packets while no proxy does.
+fn start_drain(fd: RawFd, direct: &Option<mpsc::Sender<Vec<u8>>>) -> Option<Drain> {
+    if !KILL_SWITCH.load(Ordering::SeqCst) {
+        return None;
+    }
+    let stop = CancellationToken::new();
+    let drain_stop = stop.clone();
+    let direct = direct.clone();
+    let handle = tokio::spawn(async move {
+        if let Err(e) = killswitch::drain(fd, direct, drain_stop).await {
+            crate::log_to_java(&format!("VPN >> KILL_SWITCH_DRAIN_ERR: {}", e));
+            health::record_error("KILL_SWITCH_DRAIN_FAILED", e.to_string());
+        }
+    });
+    Some((stop, handle))
+}
+
+/// Hands the TUN back to tun2proxy: the drain must be gone before anyone else reads.
+async fn stop_drain(drain: &mut Option<Drain>) {
+    if let Some((stop, handle)) = drain.take() {
+        stop.cancel();
+        let _ = handle.await;
+    }
+}
+
+/// After a fatal error the kill switch keeps the TUN up, and swallowing packets, until
+/// Kotlin tears it down.
+async fn hold_until_closed(drain: Option<Drain>) {
+    let (stop, mut handle) = match drain {
+        Some(drain) => drain,
+        None => return,
+    };
+    crate::log_to_java("VPN >> KILL_SWITCH: HOLDING_TUN");
+    // A new session may get the same fd number for its TUN; it must not find us reading it
+    let session = VPN_SESSION.load(Ordering::SeqCst);
+    tokio::select! {
+        _ = &mut handle => {}
+        _ = async {
+            while VPN_SESSION.load(Ordering::SeqCst) == session {
+                tokio::time::sleep(Duration::from_secs(1)).await;
             }
-            Err(_) => break,
+        } => {
+            stop.cancel();
+            let _ = handle.await;
         }
     }
-    crate::log_to_java("VPN >> PASSIVE_SHIELD_DOWN");
-    CORE_STATUS.store(0, Ordering::SeqCst);
 }
 
-pub fn start_vpn_loop(fd: i32) {
+/// Runs the passive shield on its own session runtime until `stop` fires or the fd closes.
+pub fn run_passive_shield(fd: RawFd, stop: CancellationToken) {
+    health::session_started();
+    // The shield watches `stop` itself, so it winds down and reports what it saw
+    crate::runtime::run_session(run_passive_shield_internal(fd, stop));
+}
+
+pub fn start_vpn_loop(fd: i32, stop: CancellationToken) {
+    VPN_SESSION.fetch_add(1, Ordering::SeqCst);
+    health::session_started();
     CORE_STATUS.store(1, Ordering::SeqCst);
     crate::log_to_java("VPN >> STARTING_LOOP");
     
@@ -254,123 +425,185 @@ pub fn start_vpn_loop(fd: i32) {
         crate::log_to_java(&format!("VPN >> WARN_NONBLOCK: {}", e));
     }
 
-    TOKIO_RT.block_on(async {
-        let secure_key = match OUTLINE_KEY.read() {
-            Ok(guard) => guard.clone(),
-            Err(_) => {
-                CORE_STATUS.store(3, Ordering::SeqCst);
-                crate::log_to_java("VPN >> ERR: KEY_READ_FAILED");
-                return;
+    crate::runtime::run_session(async {
+        // Whatever the session was doing is dropped with it; the runtime teardown ends its tasks
+        tokio::select! {
+            _ = vpn_session(fd, stop.clone()) => {}
+            _ = stop.cancelled() => {
+                killswitch::set_upstream_up(false);
+                UDP_MODE.store(UdpMode::Unknown as u8, Ordering::Relaxed);
+                crate::log_to_java("VPN >> LOOP_STOPPED");
+                CORE_STATUS.store(0, Ordering::SeqCst);
             }
-        };
-
-        if secure_key.key.is_empty() {
-            crate::log_to_java("VPN >> EMPTY_KEY: STARTING_PASSIVE_SHIELD");
-            run_passive_shield_internal(fd).await;
-            return;
         }
+    });
+}
 
-        let port = find_free_port().unwrap_or(10808);
-        PROXY_PORT.store(port, Ordering::Relaxed);
-        
-        let local_addr_str = format!("127.0.0.1:{}", port);
-        let ss_key = secure_key.key.clone();
-
-        let ss_local_addr = local_addr_str.clone();
-        tokio::spawn(async move {
-            match ServerConfig::from_url(&ss_key) {
-                Ok(server_config) => {
-                    let mut config = Config::new(ConfigType::Local);
-                    if let Ok(local_addr) = ServerAddr::from_str(&ss_local_addr) {
-                        let mut local_config = LocalConfig::new(ProtocolType::Socks);
-                        local_config.addr = Some(local_addr);
-                        local_config.mode = Mode::TcpAndUdp;
-                        config.local.push(LocalInstanceConfig { config: local_config, acl: None });
-                        config.server.push(ServerInstanceConfig::with_server_config(server_config));
-                        crate::log_to_java(&format!("VPN >> SS_LOCAL_READY_ON_{}", ss_local_addr));
-                        if let Err(e) = run_ss_local(config).await {
-                            crate::log_to_java(&format!("VPN >> SS_ERR: {}", e));
-                        }
-                    }
-                }
-                Err(e) => {
-                    crate::log_to_java(&format!("VPN >> INVALID_SS_KEY: {}", e));
-                }
-            }
-        });
-
-        // Wait for SOCKS5 proxy to be ready with retries
-        let mut proxy_ready = false;
-        for i in 1..=10 {
-            tokio::time::sleep(Duration::from_millis(300)).await;
-            if tokio::net::TcpStream::connect(local_addr_str.clone()).await.is_ok() {
-                proxy_ready = true;
-                break;
-            }
-            crate::log_to_java(&format!("VPN >> WAITING_FOR_SOCKS5 ({}/10)", i));
-        }
+async fn vpn_session(fd: RawFd, stop: CancellationToken) {
+    // Everything this session needs from the config, read once
+    let config = crate::config::snapshot();
+    let pool = SERVER_POOL.read().map(|p| p.clone()).unwrap_or_default();
+
+    if config.key.key.is_empty() && pool.is_empty() {
+        crate::log_to_java("VPN >> EMPTY_KEY: STARTING_PASSIVE_SHIELD");
+        run_passive_shield_internal(fd, stop).await;
+        return;
+    }
+
+    UPSTREAM_UP.store(false, Ordering::SeqCst);
+    let session = CancellationToken::new();
+    let direct = spawn_direct_stack(fd, &config.tun, session.clone());
+    if KILL_SWITCH.load(Ordering::SeqCst) {
+        crate::log_to_java("VPN >> KILL_SWITCH: ARMED");
+    }
+    let mut drain = start_drain(fd, &direct);
 
-        if !proxy_ready {
-            crate::log_to_java("VPN >> ERR: SOCKS5_TIMEOUT");
+    let proxy = match prepare_upstream(&config, pool).await {
+        Ok(proxy) => proxy,
+        Err(e) => {
+            crate::log_to_java(&format!("VPN >> {}", e));
+            health::record_error("UPSTREAM_FAILED", e);
             CORE_STATUS.store(3, Ordering::SeqCst);
+            hold_until_closed(drain).await;
+            session.cancel();
             return;
         }
-        
-        let mut tun_config = tun::Configuration::default();
-        tun_config.raw_fd(fd);
-        
+    };
+    let udp_gateway = config.udp_gateway_addr();
+    // Validated with the config; the tunnel's DNS server otherwise
+    let probe_target = config
+        .kill_switch
+        .probe
+        .as_deref()
+        .and_then(|probe| crate::diagnostics::parse_target(probe, 443).ok())
+        .unwrap_or_else(|| (Args::default().dns_addr.to_string(), 53));
+
+    let mut tun_config = tun::Configuration::default();
+    tun_config.raw_fd(fd);
+    // Kotlin owns the fd, and tun2proxy may be restarted on it when UDP falls back
+    tun_config.close_fd_on_drop(false);
+    tun_config.mtu(config.tun.mtu);
+
+    let mut force_uot = false;
+    loop {
+        let udp_mode = if force_uot {
+            UdpMode::UdpOverTcp
+        } else {
+            decide_mode(probe_udp_relay(&proxy).await, udp_gateway)
+        };
+        UDP_MODE.store(udp_mode as u8, Ordering::Relaxed);
+        crate::log_to_java(&format!("VPN >> UDP_MODE: {}", udp_mode.as_str()));
+
+        stop_drain(&mut drain).await;
         crate::log_to_java("VPN >> ATTEMPTING_TUN_CREATE");
         match tun::create_as_async(&tun_config) {
             Ok(tun_device) => {
                 CORE_STATUS.store(2, Ordering::SeqCst);
+                killswitch::set_upstream_up(true);
                 crate::log_to_java("VPN >> TUN_DEVICE_READY");
-                if let Ok(proxy) = ArgProxy::try_from(format!("socks5://{}", local_addr_str).as_str()) {
-                    crate::log_to_java("VPN >> STARTING_TUN2PROXY");
-                    
-                    let token = CancellationToken::new();
-                    let mut args = Args::default();
-                    args.proxy = proxy;
-                    args.dns = ArgDns::Virtual;
-                    args.verbosity = ArgVerbosity::Off;
-                    
-                    crate::log_to_java("VPN >> ENGINE_READY");
-
-                    let monitor_token = token.clone();
-                    tokio::spawn(async move {
-                        while CORE_STATUS.load(Ordering::SeqCst) != 0 {
-                            tokio::time::sleep(Duration::from_secs(5)).await;
-                        }
-                        monitor_token.cancel();
-                    });
+                crate::log_to_java("VPN >> STARTING_TUN2PROXY");
+
+                let token = CancellationToken::new();
+                let mut args = Args::default();
+                args.proxy(proxy.clone())
+                    .dns(ArgDns::Virtual)
+                    .verbosity(ArgVerbosity::Off)
+                    .ipv6_enabled(config.tun.ipv6.is_some());
+                // Validated with the config, so the default pool only stays on a bug
+                if let Ok(pool) = config.tun.virtual_dns_pool.parse() {
+                    args.virtual_dns_pool = pool;
+                }
+                if let (UdpMode::UdpOverTcp, Some(gateway)) = (udp_mode, udp_gateway) {
+                    args.udpgw_server(gateway);
+                }
 
-                    let mut lock_down_active = false;
-                    if let Ok(guard) = ALLOWED_UIDS.read() {
-                        if !guard.is_empty() {
-                            lock_down_active = true;
+                crate::log_to_java("VPN >> ENGINE_READY");
+
+                let udp_lost = Arc::new(AtomicBool::new(false));
+                let monitor_token = token.clone();
+                let monitor_udp_lost = udp_lost.clone();
+                let monitor_proxy = proxy.clone();
+                let (probe_host, probe_port) = probe_target.clone();
+                tokio::spawn(async move {
+                    let mut ticks = 0u32;
+                    while CORE_STATUS.load(Ordering::SeqCst) != 0 && !monitor_token.is_cancelled() {
+                        tokio::time::sleep(MONITOR_TICK).await;
+                        ticks += 1;
+                        if KILL_SWITCH.load(Ordering::SeqCst) {
+                            let probe = probe_remote_path(&monitor_proxy, &probe_host, probe_port);
+                            let result = match tokio::time::timeout(Duration::from_secs(3), probe).await {
+                                Ok(result) => result.map_err(|e| e.to_string()),
+                                Err(_) => Err("TIMEOUT".to_string()),
+                            };
+                            if let Err(e) = &result {
+                                if UPSTREAM_UP.load(Ordering::SeqCst) {
+                                    crate::log_to_java(&format!("VPN >> UPSTREAM_PROBE_FAILED: {}", e));
+                                    health::record_error("UPSTREAM_PROBE_FAILED", e.clone());
+                                }
+                            }
+                            killswitch::set_upstream_up(result.is_ok());
+                        }
+                        // Re-check the relay every 6 ticks; only worth it if there is a fallback
+                        if udp_mode == UdpMode::Native && udp_gateway.is_some() && ticks.is_multiple_of(6)
+                            && !probe_udp_relay(&monitor_proxy).await
+                        {
+                            monitor_udp_lost.store(true, Ordering::SeqCst);
+                            break;
                         }
                     }
+                    monitor_token.cancel();
+                });
 
-                    if lock_down_active {
-                        crate::log_to_java("SHIELD >> LOCKDOWN_FILTER: ENABLED");
-                    } else {
-                        crate::log_to_java("SHIELD >> LOCKDOWN_FILTER: DISABLED (GLOBAL)");
+                let mut lock_down_active = false;
+                if let Ok(guard) = ALLOWED_UIDS.read() {
+                    if !guard.is_empty() {
+                        lock_down_active = true;
                     }
+                }
 
-                    let filtered_tun = FilteredTun { inner: tun_device };
-
-                    if let Err(e) = run_tun2proxy(filtered_tun, 1280, args, token).await {
-                        crate::log_to_java(&format!("VPN >> EXIT: {}", e));
-                    }
+                if lock_down_active {
+                    crate::log_to_java("SHIELD >> LOCKDOWN_FILTER: ENABLED");
                 } else {
-                    crate::log_to_java("VPN >> ERR: INVALID_PROXY_URL");
+                    crate::log_to_java("SHIELD >> LOCKDOWN_FILTER: DISABLED (GLOBAL)");
+                }
+
+                let mut filtered_tun = FilteredTun::new(tun_device, direct.clone(), crate::procnet::find_uid_by_port);
+                if let Ok(pool) = crate::cidr::parse_cidr(&config.tun.virtual_dns_pool) {
+                    filtered_tun = filtered_tun.with_virtual_dns_pool(pool);
+                }
+                let tun_subnets: Vec<(std::net::IpAddr, u8)> = std::iter::once(&config.tun.ipv4)
+                    .chain(config.tun.ipv6.as_ref())
+                    .filter_map(|cidr| crate::cidr::parse_cidr(cidr).ok())
+                    .collect();
+                filtered_tun = filtered_tun.with_tun_subnets(&tun_subnets);
+
+                health::set_virtual_dns(Some(config.tun.virtual_dns_pool.clone()));
+                if let Err(e) = run_tun2proxy(filtered_tun, config.tun.mtu, args, token).await {
+                    crate::log_to_java(&format!("VPN >> EXIT: {}", e));
+                    health::record_error("TUN2PROXY_EXIT", e.to_string());
+                }
+                health::set_virtual_dns(None);
+                killswitch::set_upstream_up(false);
+
+                if udp_lost.load(Ordering::SeqCst) && CORE_STATUS.load(Ordering::SeqCst) != 0 {
+                    crate::log_to_java("VPN >> UDP_RELAY_LOST: SWITCHING_TO_UOT");
+                    health::record_error("UDP_RELAY_LOST", "SWITCHING_TO_UOT");
+                    drain = start_drain(fd, &direct);
+                    force_uot = true;
+                    continue;
                 }
             }
             Err(e) => {
                 crate::log_to_java(&format!("VPN >> TUN_CREATE_FAILED: {}", e));
+                health::record_error("TUN_CREATE_FAILED", e.to_string());
                 CORE_STATUS.store(3, Ordering::SeqCst);
+                hold_until_closed(start_drain(fd, &direct)).await;
             }
         }
-        crate::log_to_java("VPN >> LOOP_STOPPED");
-        CORE_STATUS.store(0, Ordering::SeqCst);
-    });
+        break;
+    }
+    session.cancel();
+    UDP_MODE.store(UdpMode::Unknown as u8, Ordering::Relaxed);
+    crate::log_to_java("VPN >> LOOP_STOPPED");
+    CORE_STATUS.store(0, Ordering::SeqCst);
 }